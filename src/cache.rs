@@ -0,0 +1,81 @@
+extern crate serde;
+extern crate serde_json;
+extern crate url;
+
+use std;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::time::{Duration, SystemTime};
+
+use self::url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+/// Values kept as JSON files in a directory, one per key, and ignored once
+/// older than the TTL.
+pub struct Cache {
+    dir: std::path::PathBuf,
+    ttl: Duration,
+}
+
+impl Cache {
+    pub fn new(dir: std::path::PathBuf, ttl: Duration) -> Cache {
+        Cache { dir, ttl }
+    }
+
+    /// The value stored for `key`, if it's still fresh.
+    pub fn get<T>(&self, key: &str) -> Option<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let path = self.path(key);
+        let modified = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+            Ok(v) => v,
+            Err(_) => return None,
+        };
+        match SystemTime::now().duration_since(modified) {
+            Ok(age) if age <= self.ttl => (),
+            _ => {
+                debug3!("Cache entry {} expired", key);
+                return None;
+            }
+        };
+        let file = match std::fs::File::open(&path) {
+            Ok(v) => v,
+            Err(_) => return None,
+        };
+        match serde_json::from_reader(file) {
+            Ok(v) => {
+                debug2!("Using cached {}", key);
+                Some(v)
+            }
+            Err(e) => {
+                debug!("Ignoring bad cache entry {}: {}", path.to_string_lossy(), e);
+                None
+            }
+        }
+    }
+
+    pub fn set<T>(&self, key: &str, value: &T) -> std::io::Result<()>
+    where
+        T: serde::Serialize,
+    {
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)?;
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(self.path(key))?;
+        serde_json::to_writer(file, value).map_err(std::io::Error::from)
+    }
+
+    pub fn remove(&self, key: &str) {
+        debug2!("Removing cached {}", key);
+        let _ = std::fs::remove_file(self.path(key));
+    }
+
+    fn path(&self, key: &str) -> std::path::PathBuf {
+        // encoding '/' and '%' keeps each key a distinct file name
+        self.dir.join(
+            utf8_percent_encode(key, PATH_SEGMENT_ENCODE_SET).to_string(),
+        )
+    }
+}
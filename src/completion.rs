@@ -0,0 +1,273 @@
+extern crate getopts;
+
+use std;
+use std::fmt;
+use std::str::FromStr;
+
+// commands that can be given before the options
+const COMMANDS: &'static str = "completion find keys login logout ls";
+
+const BASH: &'static str = r#"# bash completion for rsh, generated by `rsh completion bash`
+_rsh() {
+    # COMP_WORDS splits on ':', so take the word from the line instead
+    local word="${COMP_LINE:0:COMP_POINT}"
+    word="${word##*[[:space:]]}"
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+
+    case "$prev" in
+@FILE_OPTIONS@)
+            COMPREPLY=($(compgen -f -- "$word"))
+            return
+            ;;
+@VALUE_OPTIONS@)
+            return
+            ;;
+        completion)
+            COMPREPLY=($(compgen -W "bash fish zsh" -- "$word"))
+            return
+            ;;
+    esac
+
+    if [[ "$word" == -* ]]; then
+        COMPREPLY=($(compgen -W "@OPTIONS@" -- "$word"))
+        return
+    fi
+
+    local candidates="$(rsh __complete "$word" 2>/dev/null)"
+    if [[ $COMP_CWORD -eq 1 ]]; then
+        candidates="@COMMANDS@ $candidates"
+    fi
+    COMPREPLY=($(compgen -W "$candidates" -- "$word"))
+
+    # bash only replaces what follows the last ':'
+    if [[ "$word" == *:* && "$COMP_WORDBREAKS" == *:* ]]; then
+        local colon="${word%"${word##*:}"}"
+        local i=${#COMPREPLY[@]}
+        while [[ $((--i)) -ge 0 ]]; do
+            COMPREPLY[$i]="${COMPREPLY[$i]#"$colon"}"
+        done
+    fi
+
+    # environments and stacks are followed by more of the path
+    if [[ ${#COMPREPLY[@]} -eq 1 && "${COMPREPLY[0]}" == *[/:] ]]; then
+        compopt -o nospace
+    fi
+}
+complete -F _rsh rsh
+"#;
+
+const ZSH: &'static str = r#"#compdef rsh
+# zsh completion for rsh, generated by `rsh completion zsh`
+
+_rsh_paths() {
+    if (( ${words[(I)completion]} )); then
+        compadd bash fish zsh
+        return
+    fi
+    local -a candidates
+    candidates=(${(f)"$(rsh __complete "$PREFIX" 2>/dev/null)"})
+    # environments and stacks are followed by more of the path
+    compadd -S '' -- ${(M)candidates:#*[/:]}
+    compadd -- ${candidates:#*[/:]}
+}
+
+_rsh_first() {
+    compadd @COMMANDS@
+    _rsh_paths
+}
+
+_arguments -s \
+@OPTIONS@    '1: :_rsh_first' \
+    '*: :_rsh_paths'
+"#;
+
+const FISH: &'static str = r#"# fish completion for rsh, generated by `rsh completion fish`
+function __fish_rsh_first
+    test (count (commandline -opc)) -eq 1
+end
+
+complete -c rsh -f
+complete -c rsh -n '__fish_rsh_first' -a '@COMMANDS@'
+complete -c rsh -n '__fish_seen_subcommand_from completion' -a 'bash fish zsh'
+complete -c rsh -n 'not __fish_seen_subcommand_from completion' -a '(rsh __complete (commandline -ct) 2>/dev/null)'
+@OPTIONS@"#;
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownShell(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownShell(ref name) => write!(fmt, "unknown shell {}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Fish,
+    Zsh,
+}
+
+impl FromStr for Shell {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "fish" => Ok(Shell::Fish),
+            "zsh" => Ok(Shell::Zsh),
+            _ => Err(Error::UnknownShell(s.into())),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Opt {
+    short: Option<char>,
+    long: Option<String>,
+    hint: Option<String>,
+    description: String,
+}
+
+impl Opt {
+    fn names(&self) -> Vec<String> {
+        self.short
+            .map(|c| format!("-{}", c))
+            .into_iter()
+            .chain(self.long.as_ref().map(|s| format!("--{}", s)))
+            .collect()
+    }
+
+    fn takes_file(&self) -> bool {
+        self.hint.as_ref().map(|h| h.ends_with("FILE")).unwrap_or(false)
+    }
+}
+
+/// The completion script for `shell`, covering the options in `opts`.
+pub fn script(shell: Shell, opts: &getopts::Options) -> String {
+    let opts = parse_usage(&opts.usage(""));
+    let script = match shell {
+        Shell::Bash => {
+            let names = |file: bool| {
+                let names: Vec<String> = opts.iter()
+                    .filter(|o| o.hint.is_some() && o.takes_file() == file)
+                    .flat_map(|o| o.names())
+                    .collect();
+                format!("        {}", names.join("|"))
+            };
+            let all: Vec<String> = opts.iter().flat_map(|o| o.names()).collect();
+            BASH.replace("@FILE_OPTIONS@", &names(true))
+                .replace("@VALUE_OPTIONS@", &names(false))
+                .replace("@OPTIONS@", &all.join(" "))
+        }
+        Shell::Fish => {
+            let lines: Vec<String> = opts.iter().map(fish_option).collect();
+            FISH.replace("@OPTIONS@", &lines.join(""))
+        }
+        Shell::Zsh => {
+            let lines: Vec<String> = opts.iter().map(zsh_option).collect();
+            ZSH.replace("@OPTIONS@", &lines.join(""))
+        }
+    };
+    script.replace("@COMMANDS@", COMMANDS)
+}
+
+/// Splits a word being completed into what names the host and path to list,
+/// and the segment of the path being typed. `None` when it's the host that is
+/// being typed.
+pub fn split_word(word: &str) -> Option<(&str, &str)> {
+    let start = word.find("://").map(|i| i + 3).unwrap_or(0);
+    let rest = &word[start..];
+    if let Some(i) = rest.rfind('/') {
+        let i = start + i + 1;
+        return Some((&word[..i], &word[i..]));
+    }
+    // server:path, but not host:port
+    match rest.find(':') {
+        Some(i) if start == 0 && !rest[i + 1..].starts_with(|c: char| c.is_digit(10)) => {
+            Some((&word[..i + 1], &word[i + 1..]))
+        }
+        _ => None,
+    }
+}
+
+// getopts has no way to list its options, but its usage text has every one
+// on a line starting with a '-', with the description wrapped onto the
+// following lines
+fn parse_usage(usage: &str) -> Vec<Opt> {
+    let mut opts: Vec<Opt> = Vec::new();
+    for line in usage.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('-') {
+            let (names, description) = match trimmed.find("  ") {
+                Some(i) => (&trimmed[..i], trimmed[i..].trim()),
+                None => (trimmed, ""),
+            };
+            let mut opt = Opt::default();
+            for word in names.split(|c| c == ',' || c == ' ').filter(|w| !w.is_empty()) {
+                if word.starts_with("--") {
+                    opt.long = Some(word[2..].into());
+                } else if word.starts_with('-') {
+                    opt.short = word[1..].chars().next();
+                } else {
+                    opt.hint = Some(word.into());
+                }
+            }
+            opt.description = description.into();
+            opts.push(opt);
+        } else if line.starts_with(' ') && !trimmed.is_empty() {
+            if let Some(opt) = opts.last_mut() {
+                if !opt.description.is_empty() {
+                    opt.description.push(' ');
+                }
+                opt.description.push_str(trimmed);
+            }
+        }
+    }
+    opts
+}
+
+fn fish_option(opt: &Opt) -> String {
+    let mut line = String::from("complete -c rsh");
+    if let Some(c) = opt.short {
+        line.push_str(&format!(" -s {}", c));
+    }
+    if let Some(ref name) = opt.long {
+        line.push_str(&format!(" -l {}", name));
+    }
+    if opt.takes_file() {
+        line.push_str(" -r -F");
+    } else if opt.hint.is_some() {
+        line.push_str(" -x");
+    }
+    let description = opt.description.replace('\\', "\\\\").replace('\'', "\\'");
+    line.push_str(&format!(" -d '{}'\n", description));
+    line
+}
+
+fn zsh_option(opt: &Opt) -> String {
+    let names = opt.names();
+    let description = opt.description
+        .replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]");
+    let mut spec = if names.len() > 1 {
+        format!("'({})'{{{}}}'", names.join(" "), names.join(","))
+    } else {
+        format!("'{}", names.join(""))
+    };
+    if opt.hint.is_some() && opt.short.is_some() {
+        // the value can be joined to a short option, or follow it
+        spec.push('+');
+    }
+    spec.push_str(&format!("[{}]", description));
+    if let Some(ref hint) = opt.hint {
+        let action = if opt.takes_file() { "_files" } else { " " };
+        spec.push_str(&format!(":{}:{}", hint, action));
+    }
+    format!("    {}' \\\n", spec)
+}
@@ -3,13 +3,19 @@ use std;
 extern crate nom;
 
 use std::io::Read;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
 use std::str::FromStr;
 
-pub use options::{Container, LogLevel, Protocol, RequestTTY};
+extern crate url;
+extern crate users;
+
+pub use options::{AuthProvider, Container, ControlMaster, LogLevel, Protocol, RequestTTY,
+                  StrictHostKeyChecking, WebsocketProtocol};
 use pattern;
 
 #[derive(Debug)]
 pub enum Error {
+    BadPermissions,
     IoError(std::io::Error),
     OptionError(String, String),
     OptionNotAllowed(String),
@@ -34,6 +40,28 @@ from!(std::io::Error => Error, Error::IoError);
 #[derive(Debug)]
 pub struct Config {
     sections: Vec<Section>,
+    servers: Vec<Server>,
+}
+
+/// A named Rancher server, so `rsh name:stack/service` can stand in for the
+/// full URL.
+#[derive(Clone, Debug)]
+pub struct Server {
+    pub name: String,
+    pub url: Option<url::Url>,
+    pub auth_provider: Option<AuthProvider>,
+    pub environment: Option<String>,
+}
+
+impl Server {
+    fn new(name: String) -> Server {
+        Server {
+            name,
+            url: None,
+            auth_provider: None,
+            environment: None,
+        }
+    }
 }
 
 macro_rules! search {
@@ -52,13 +80,76 @@ macro_rules! search {
 }
 
 impl Config {
+    search!(auth_provider -> AuthProvider);
+    search!(batch_mode -> bool);
+    search!(ca_certificate -> String);
+    search!(cache_ttl -> u64);
+    search!(check_host_name -> bool);
+    search!(client_certificate -> String);
+    search!(client_key -> String);
     search!(container -> Container);
+    search!(context -> String);
+    search!(control_master -> ControlMaster);
+    search!(control_path -> String);
+    search!(control_persist -> u64);
+    search!(credential_helper -> String);
+
+    /// `DefaultServer` applies regardless of the host, as it's used before
+    /// there is one.
+    pub fn default_server(&self) -> Option<String> {
+        self.sections.iter().filter_map(|s| s.default_server.to_owned()).next()
+    }
+
+    search!(encrypt_keys -> bool);
     search!(environment -> String);
     search!(escape_char -> char);
     search!(host_name -> String);
+
+    /// The hosts named in `Host` patterns, for completion.
+    pub fn hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self.sections
+            .iter()
+            .flat_map(|s| s.pattern.literals())
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+        hosts
+    }
+
     search!(log_level -> LogLevel);
+    search!(number_of_password_prompts -> u16);
     search!(port -> u16);
     search!(protocol -> Protocol);
+    search!(proxy_command -> String);
+    search!(proxy_url -> String);
+
+    /// The named server, with each setting taken from the first block that
+    /// gives it.
+    pub fn server(&self, name: &str) -> Option<Server> {
+        let mut found: Option<Server> = None;
+        for server in self.servers.iter().filter(|s| s.name == name) {
+            match found {
+                Some(ref mut acc) => {
+                    acc.url = acc.url.take().or_else(|| server.url.to_owned());
+                    acc.auth_provider = acc.auth_provider.or(server.auth_provider);
+                    acc.environment = acc.environment.take().or_else(|| {
+                        server.environment.to_owned()
+                    });
+                }
+                None => found = Some(server.to_owned()),
+            }
+        }
+        found
+    }
+
+    /// The names of all `Server` blocks.
+    pub fn server_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.servers.iter().map(|s| s.name.to_owned()).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
     search!(remote_command -> String);
     search!(request_tty -> RequestTTY);
     pub fn send_env(&self, host: &str) -> Vec<pattern::Pattern> {
@@ -70,13 +161,18 @@ impl Config {
     }
     search!(service -> String);
     search!(stack -> String);
+    search!(strict_host_key_checking -> StrictHostKeyChecking);
+    search!(strict_modes -> bool);
     search!(user -> String);
+    search!(websocket_host -> String);
+    search!(websocket_protocol -> WebsocketProtocol);
 
     pub fn try_from(options: Vec<&str>) -> Result<Config, Error> {
         let mut parsed = Vec::new();
         for opt in options {
             match pair(&opt) {
-                nom::IResult::Done(_, (key, _)) if key.eq_ignore_ascii_case("host") => {
+                nom::IResult::Done(_, (key, _)) if key.eq_ignore_ascii_case("host") ||
+                                                   key.eq_ignore_ascii_case("server") => {
                     return Err(Error::OptionNotAllowed(key.into()))
                 }
                 nom::IResult::Done(_, pair) => parsed.push(pair),
@@ -89,13 +185,17 @@ impl Config {
 
     pub fn append(mut self, mut other: Config) -> Self {
         self.sections.append(&mut other.sections);
+        self.servers.append(&mut other.servers);
         self
     }
 }
 
 impl Default for Config {
     fn default() -> Config {
-        Config { sections: Vec::new() }
+        Config {
+            sections: Vec::new(),
+            servers: Vec::new(),
+        }
     }
 }
 
@@ -117,6 +217,31 @@ pub fn user_config_dir() -> std::path::PathBuf {
     config_dir
 }
 
+/// Creates `~/.rsh` readable only by the user, as it holds API keys.
+pub fn create_user_config_dir() -> std::io::Result<()> {
+    let path = user_config_dir();
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&path)?;
+    let mut permissions = std::fs::metadata(&path)?.permissions();
+    if permissions.mode() & 0o077 != 0 {
+        debug!("Restricting permissions of {}", path.to_string_lossy());
+        permissions.set_mode(0o700);
+        std::fs::set_permissions(&path, permissions)?;
+    }
+    Ok(())
+}
+
+pub fn known_hosts_path() -> std::path::PathBuf {
+    let mut known_hosts_path = user_config_dir();
+    known_hosts_path.push("known_hosts");
+    known_hosts_path
+}
+
+pub fn cache_dir() -> std::path::PathBuf {
+    let mut cache_dir = user_config_dir();
+    cache_dir.push("cache");
+    cache_dir
+}
+
 pub fn user_config_path() -> std::path::PathBuf {
     let mut config_path = user_config_dir();
     config_path.push("config");
@@ -133,9 +258,26 @@ pub fn api_key_path(file: &str) -> std::path::PathBuf {
     config_path
 }
 
-pub fn open_config(path: &std::path::PathBuf) -> Result<Config, Error> {
+pub fn rancher_cli_config_path() -> std::path::PathBuf {
+    let mut config_path = std::env::home_dir().unwrap_or(std::path::PathBuf::from("/"));
+    config_path.push(".rancher");
+    config_path.push("cli.json");
+    config_path
+}
+
+/// Reads the config file at `path`, with `strict_modes` refusing files that
+/// could have been changed by someone else.
+pub fn open_config(path: &std::path::PathBuf, strict_modes: bool) -> Result<Config, Error> {
     match std::fs::File::open(path).map(std::io::BufReader::new) {
         Ok(mut reader) => {
+            if strict_modes {
+                let metadata = reader.get_ref().metadata()?;
+                if metadata.mode() & 0o022 != 0 ||
+                    (metadata.uid() != 0 && metadata.uid() != users::get_current_uid())
+                {
+                    return Err(Error::BadPermissions);
+                }
+            }
             let mut string = String::new();
             reader.read_to_string(&mut string)?;
             Ok(string.parse()?)
@@ -157,25 +299,71 @@ macro_rules! assign {
     }
 }
 
+macro_rules! assign_flag {
+    ( $name:expr, $lhs:expr => $rhs:expr ) => {
+        {
+            match $rhs.to_lowercase().as_ref() {
+                "yes" | "true" => $lhs = Some(true),
+                "no" | "false" => $lhs = Some(false),
+                _ => return Err(Error::OptionError($name.into(), $rhs.into())),
+            };
+        }
+    }
+}
+
 fn build_config(pairs: Vec<(&str, &str)>) -> Result<Config, Error> {
     let mut sections = Vec::new();
+    let mut servers = Vec::new();
     let mut current = Section::default();
+    let mut server: Option<Server> = None;
     for (key, value) in pairs {
         match key.to_lowercase().as_ref() {
             "host" => {
+                servers.extend(server.take());
                 sections.push(current);
                 match value.parse() {
                     Ok(v) => current = Section::new(v),
                     Err(_) => return Err(Error::OptionError(key.into(), value.into())),
                 };
             }
+            "server" => {
+                servers.extend(server.take());
+                if value.is_empty() {
+                    return Err(Error::OptionError(key.into(), value.into()));
+                }
+                server = Some(Server::new(value.into()));
+            }
+            _ if server.is_some() => {
+                if let Some(ref mut current) = server {
+                    build_server(current, key, value)?;
+                }
+            }
+            "authprovider" => assign!(key, current.auth_provider => value),
+            "batchmode" => assign_flag!(key, current.batch_mode => value),
+            "cacertificate" => assign!(key, current.ca_certificate => value),
+            "cachettl" => assign!(key, current.cache_ttl => value),
+            "checkhostname" => assign_flag!(key, current.check_host_name => value),
+            "clientcertificate" => assign!(key, current.client_certificate => value),
+            "clientkey" => assign!(key, current.client_key => value),
             "container" => assign!(key, current.container => value),
+            "context" => assign!(key, current.context => value),
+            "controlmaster" => assign!(key, current.control_master => value),
+            "controlpath" => assign!(key, current.control_path => value),
+            "controlpersist" => assign!(key, current.control_persist => value),
+            "credentialhelper" => assign!(key, current.credential_helper => value),
+            "defaultserver" => assign!(key, current.default_server => value),
+            "encryptkeys" => assign_flag!(key, current.encrypt_keys => value),
             "environment" => assign!(key, current.environment => value),
             "escapechar" => assign!(key, current.escape_char => value),
             "hostname" => assign!(key, current.host_name => value),
             "loglevel" => assign!(key, current.log_level => value),
+            "numberofpasswordprompts" => {
+                assign!(key, current.number_of_password_prompts => value)
+            }
             "port" => assign!(key, current.port => value),
             "protocol" => assign!(key, current.protocol => value),
+            "proxycommand" => assign!(key, current.proxy_command => value),
+            "proxyurl" => assign!(key, current.proxy_url => value),
             "remotecommand" => assign!(key, current.remote_command => value),
             "requesttty" => assign!(key, current.request_tty => value),
             "sendenv" => {
@@ -190,30 +378,69 @@ fn build_config(pairs: Vec<(&str, &str)>) -> Result<Config, Error> {
             }
             "service" => assign!(key, current.service => value),
             "stack" => assign!(key, current.stack => value),
+            "stricthostkeychecking" => {
+                assign!(key, current.strict_host_key_checking => value)
+            }
+            "strictmodes" => assign_flag!(key, current.strict_modes => value),
             "user" => assign!(key, current.user => value),
+            "websockethost" => assign!(key, current.websocket_host => value),
+            "websocketprotocol" => assign!(key, current.websocket_protocol => value),
             _ => return Err(Error::UnknownOption(key.into())),
         }
     }
+    servers.extend(server);
     sections.push(current);
-    Ok(Config { sections })
+    Ok(Config { sections, servers })
+}
+
+/// Assigns an option within a `Server` block, which only takes a few.
+fn build_server(current: &mut Server, key: &str, value: &str) -> Result<(), Error> {
+    match key.to_lowercase().as_ref() {
+        "authprovider" => assign!(key, current.auth_provider => value),
+        "environment" => assign!(key, current.environment => value),
+        "url" => assign!(key, current.url => value),
+        _ => return Err(Error::UnknownOption(key.into())),
+    };
+    Ok(())
 }
 
 #[derive(Debug, Default)]
 struct Section {
     pattern: pattern::PatternList,
+    auth_provider: Option<AuthProvider>,
+    batch_mode: Option<bool>,
+    ca_certificate: Option<String>,
+    cache_ttl: Option<u64>,
+    check_host_name: Option<bool>,
+    client_certificate: Option<String>,
+    client_key: Option<String>,
     container: Option<Container>,
+    context: Option<String>,
+    control_master: Option<ControlMaster>,
+    control_path: Option<String>,
+    control_persist: Option<u64>,
+    credential_helper: Option<String>,
+    default_server: Option<String>,
+    encrypt_keys: Option<bool>,
     environment: Option<String>,
     escape_char: Option<char>,
     host_name: Option<String>,
     log_level: Option<LogLevel>,
+    number_of_password_prompts: Option<u16>,
     port: Option<u16>,
     protocol: Option<Protocol>,
+    proxy_command: Option<String>,
+    proxy_url: Option<String>,
     remote_command: Option<String>,
     request_tty: Option<RequestTTY>,
     send_env: Vec<pattern::Pattern>,
     service: Option<String>,
     stack: Option<String>,
+    strict_host_key_checking: Option<StrictHostKeyChecking>,
+    strict_modes: Option<bool>,
     user: Option<String>,
+    websocket_host: Option<String>,
+    websocket_protocol: Option<WebsocketProtocol>,
 }
 
 impl Section {
@@ -0,0 +1,133 @@
+extern crate nix;
+extern crate serde_json;
+
+use std;
+use std::io::{BufRead, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::{Duration, Instant};
+
+use self::nix::poll::{poll, EventFlags, PollFd};
+use self::nix::sys::stat::{umask, Mode};
+use self::nix::unistd::{dup2, fork, setsid, ForkResult};
+
+use rancher::HostAccess;
+
+/// What a client asks the control master to start, the same as given on the
+/// command line.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Request {
+    pub environment: Option<String>,
+    pub stack: Option<String>,
+    pub service: String,
+    pub command: Vec<String>,
+    pub tty: bool,
+}
+
+/// Asks the control master listening at `path` to start `request`, giving
+/// the websocket to connect to, or why the master couldn't.
+pub fn request(
+    path: &std::path::Path,
+    request: &Request,
+) -> std::io::Result<Result<HostAccess, String>> {
+    let mut stream = UnixStream::connect(path)?;
+    serde_json::to_writer(&mut stream, request)?;
+    stream.write_all(b"\n")?;
+    let mut line = String::new();
+    std::io::BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Whether a control master is accepting connections at `path`.
+pub fn is_running(path: &std::path::Path) -> bool {
+    UnixStream::connect(path).is_ok()
+}
+
+/// Listens at `path`, replacing a socket left behind by a master that has
+/// gone away. Fails with `AddrInUse` when a master is still running there.
+pub fn listen(path: &std::path::Path) -> std::io::Result<UnixListener> {
+    if path.exists() {
+        if is_running(path) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "a control master is already running",
+            ));
+        }
+        debug!("Removing stale control socket {}", path.to_string_lossy());
+        std::fs::remove_file(path)?;
+    }
+    // created without access for others, rather than restricted after
+    let old_umask = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(path);
+    umask(old_umask);
+    listener
+}
+
+/// Runs `master` in a new background process, detached from the terminal.
+pub fn spawn<F>(master: F) -> nix::Result<()>
+where
+    F: FnOnce(),
+{
+    match fork()? {
+        ForkResult::Parent { .. } => Ok(()),
+        ForkResult::Child => {
+            setsid()?;
+            if let Ok(null) = std::fs::OpenOptions::new().read(true).write(true).open("/dev/null") {
+                for fd in 0..3 {
+                    let _ = dup2(null.as_raw_fd(), fd);
+                }
+            }
+            master();
+            std::process::exit(0);
+        }
+    }
+}
+
+/// Answers requests on `listener` with `handle`, one at a time, until none
+/// have come for `persist`.
+pub fn serve<F>(listener: UnixListener, path: &std::path::Path, persist: Duration, handle: F)
+where
+    F: Fn(Request) -> Result<HostAccess, String>,
+{
+    let mut last_request = Instant::now();
+    loop {
+        let idle = last_request.elapsed();
+        if idle >= persist {
+            break;
+        }
+        let remaining = persist - idle;
+        let timeout = remaining.as_secs() * 1000 + u64::from(remaining.subsec_nanos() / 1_000_000);
+        let mut fds = [PollFd::new(listener.as_raw_fd(), EventFlags::POLLIN)];
+        match poll(&mut fds, std::cmp::min(timeout, i32::max_value() as u64) as i32) {
+            Ok(0) => continue,
+            Ok(_) => (),
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(_) => break,
+        };
+
+        let stream = match listener.accept() {
+            Ok((v, _)) => v,
+            Err(_) => continue,
+        };
+        last_request = Instant::now();
+        if let Err(e) = answer(stream, &handle) {
+            debug!("Control request failed: {}", e);
+        }
+    }
+    let _ = std::fs::remove_file(path);
+}
+
+fn answer<F>(stream: UnixStream, handle: &F) -> std::io::Result<()>
+where
+    F: Fn(Request) -> Result<HostAccess, String>,
+{
+    let mut line = String::new();
+    std::io::BufReader::new(&stream).read_line(&mut line)?;
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => handle(request),
+        Err(e) => Err(format!("bad request: {}", e)),
+    };
+    let mut stream = stream;
+    serde_json::to_writer(&mut stream, &response)?;
+    stream.write_all(b"\n")
+}
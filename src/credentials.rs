@@ -0,0 +1,424 @@
+extern crate base64;
+extern crate openssl;
+extern crate serde_json;
+extern crate url;
+
+use std;
+use std::fmt;
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+
+use self::openssl::{hash, pkcs5, rand, symm};
+
+use config;
+use prompt;
+use rancher::ApiKey;
+
+// PBKDF2 rounds deriving the key that encrypts stored API keys
+const KDF_ROUNDS: usize = 100_000;
+
+/// The Rancher CLI's configuration, either a map of named servers or, from
+/// older versions of the CLI, a single server.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RancherCliConfig {
+    Servers {
+        #[serde(rename = "Servers")]
+        servers: std::collections::BTreeMap<String, RancherCliServer>,
+        #[serde(rename = "CurrentServer", default)]
+        current_server: String,
+    },
+    Server(RancherCliServer),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RancherCliServer {
+    #[serde(default)]
+    access_key: String,
+    #[serde(default)]
+    secret_key: String,
+    url: String,
+}
+
+impl RancherCliConfig {
+    /// The configured servers by name, the current server first.
+    fn servers(self) -> Vec<(String, RancherCliServer)> {
+        match self {
+            RancherCliConfig::Servers { servers, current_server } => {
+                let mut servers: Vec<_> = servers.into_iter().collect();
+                servers.sort_by_key(|&(ref name, _)| name != &current_server);
+                servers
+            }
+            RancherCliConfig::Server(server) => vec![(String::new(), server)],
+        }
+    }
+}
+
+/// An API key encrypted with a key derived from a passphrase.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EncryptedApiKey {
+    salt: String,
+    nonce: String,
+    tag: String,
+    ciphertext: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum KeyFileContents {
+    Encrypted(EncryptedApiKey),
+    Plain(ApiKey),
+}
+
+/// Where API keys obtained by logging in are kept, either rsh's own key file
+/// or a git-style credential helper command.
+#[derive(Clone)]
+pub enum KeyStore {
+    File(KeyFile),
+    Helper(String),
+}
+
+#[derive(Clone)]
+pub struct KeyFile {
+    pub path: std::path::PathBuf,
+    pub encrypt: bool, // ask for a passphrase to encrypt the key with
+    pub strict_modes: bool, // ignore the file if others can read it
+    pub batch_mode: bool, // fail rather than ask for the passphrase
+}
+
+impl fmt::Display for KeyStore {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match self {
+            &KeyStore::File(ref file) => file.path.to_string_lossy().fmt(fmt),
+            &KeyStore::Helper(ref command) => write!(fmt, "credential helper {}", command),
+        }
+    }
+}
+
+/// The API keys for the server at `url`, in the order to try them: from the
+/// `RANCHER_*` environment variables, the Rancher CLI's config, and the key
+/// store. Keys that are rejected give way to the next, so a key stored after
+/// logging in again is used even when an older one is still set. The key
+/// store, which may ask for a passphrase, is only read when it's reached.
+pub fn load(url: &url::Url, key_store: KeyStore) -> Box<Iterator<Item = ApiKey>> {
+    let host = host_with_port(url);
+    let url = url.clone();
+    Box::new(
+        from_environment(&host)
+            .into_iter()
+            .chain(from_rancher_cli(&host))
+            .chain(std::iter::once(()).filter_map(move |_| {
+                match load_stored(&url, &key_store) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("Couldn't read {}: {}.", key_store, e);
+                        None
+                    }
+                }
+            })),
+    )
+}
+
+/// Finds the API key for the server at `url` in the key store only.
+pub fn load_stored(url: &url::Url, key_store: &KeyStore) -> std::io::Result<Option<ApiKey>> {
+    match key_store {
+        &KeyStore::File(ref file) => from_key_file(file),
+        &KeyStore::Helper(ref command) => Ok(from_helper(command, url)),
+    }
+}
+
+pub fn store(url: &url::Url, key_store: &KeyStore, api_key: &ApiKey) -> std::io::Result<()> {
+    debug!("Writing {}", key_store);
+    match key_store {
+        &KeyStore::File(ref file) => {
+            let json_string = if file.encrypt {
+                serde_json::to_string(&encrypt(api_key)?).expect("failed to construct json")
+            } else {
+                serde_json::to_string(api_key).expect("failed to construct json")
+            };
+            let handle = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&file.path)?;
+            // mode only applies to new files, fix up any written before
+            let mut permissions = handle.metadata()?.permissions();
+            if permissions.mode() & 0o077 != 0 {
+                permissions.set_mode(0o600);
+                handle.set_permissions(permissions)?;
+            }
+            let mut writer = std::io::BufWriter::new(handle);
+            writer.write_all(json_string.as_bytes())
+        }
+        &KeyStore::Helper(ref command) => {
+            run_helper(command, "store", &helper_input(url, Some(api_key))).map(|_| ())
+        }
+    }
+}
+
+pub fn remove(url: &url::Url, key_store: &KeyStore, api_key: &ApiKey) -> std::io::Result<()> {
+    debug!("Removing {}", key_store);
+    match key_store {
+        &KeyStore::File(ref file) => std::fs::remove_file(&file.path),
+        &KeyStore::Helper(ref command) => {
+            run_helper(command, "erase", &helper_input(url, Some(api_key))).map(|_| ())
+        }
+    }
+}
+
+fn from_environment(host: &str) -> Option<ApiKey> {
+    let access_key = std::env::var("RANCHER_ACCESS_KEY").ok()?;
+    let secret_key = std::env::var("RANCHER_SECRET_KEY").ok()?;
+    match std::env::var("RANCHER_URL") {
+        Ok(ref v) if !same_server(v, host) => {
+            debug!("RANCHER_URL {} is not {}, ignoring RANCHER_ACCESS_KEY", v, host);
+            None
+        }
+        _ => {
+            debug!("Reading Rancher API key from RANCHER_ACCESS_KEY");
+            Some(ApiKey::new(access_key, secret_key))
+        }
+    }
+}
+
+fn from_rancher_cli(host: &str) -> Option<ApiKey> {
+    let (_, server) = read_rancher_cli()?.into_iter().find(|&(_, ref server)| {
+        !server.access_key.is_empty() && same_server(&server.url, host)
+    })?;
+    debug!(
+        "Reading Rancher API key from {}",
+        config::rancher_cli_config_path().to_string_lossy()
+    );
+    Some(ApiKey::new(server.access_key, server.secret_key))
+}
+
+/// The server named `name` in the Rancher CLI's configuration, for use when
+/// rsh's own configuration doesn't define it.
+pub fn rancher_cli_server(name: &str) -> Option<config::Server> {
+    let (_, server) = read_rancher_cli()?.into_iter().find(|&(ref v, _)| v == name)?;
+    debug!("Using server {} from the Rancher CLI configuration", name);
+    Some(config::Server {
+        name: name.to_string(),
+        url: url::Url::parse(&server.url).ok(),
+        auth_provider: None,
+        environment: None,
+    })
+}
+
+fn read_rancher_cli() -> Option<Vec<(String, RancherCliServer)>> {
+    let path = config::rancher_cli_config_path();
+    let mut string = String::new();
+    match std::fs::File::open(&path) {
+        Ok(mut file) => file.read_to_string(&mut string).ok()?,
+        Err(_) => return None,
+    };
+    match serde_json::from_str::<RancherCliConfig>(&string) {
+        Ok(v) => Some(v.servers()),
+        Err(_) => {
+            debug!("{}: Error parsing config.", path.to_string_lossy());
+            None
+        }
+    }
+}
+
+fn from_key_file(file: &KeyFile) -> std::io::Result<Option<ApiKey>> {
+    let api_key_path = &file.path;
+    debug!(
+        "Reading Rancher API key from {}",
+        api_key_path.to_string_lossy()
+    );
+    let mut reader = match std::fs::File::open(api_key_path).map(std::io::BufReader::new) {
+        Ok(v) => v,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!(
+                "{} No such file or directory",
+                api_key_path.to_string_lossy()
+            );
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    let mode = reader.get_ref().metadata()?.mode();
+    if file.strict_modes && mode & 0o077 != 0 {
+        error!(
+            "Permissions {:04o} for '{}' are too open.",
+            mode & 0o777,
+            api_key_path.to_string_lossy()
+        );
+        error!("It is required that your API key files are NOT accessible by others.");
+        error!("This API key will be ignored.");
+        return Ok(None);
+    }
+    let mut string = String::new();
+    reader.read_to_string(&mut string)?;
+    let contents = serde_json::from_str(&string).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    })?;
+    match contents {
+        KeyFileContents::Plain(v) => Ok(Some(v)),
+        KeyFileContents::Encrypted(_) if file.batch_mode => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "the API key is encrypted and BatchMode is set",
+        )),
+        KeyFileContents::Encrypted(v) => {
+            let prompt = format!("Enter passphrase for {}", api_key_path.to_string_lossy());
+            decrypt(&v, &prompt).map(Some)
+        }
+    }
+}
+
+fn encrypt(api_key: &ApiKey) -> std::io::Result<EncryptedApiKey> {
+    let passphrase = prompt::prompt_password("Enter passphrase to encrypt the API key")?;
+    if passphrase != prompt::prompt_password("Enter same passphrase again")? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "passphrases do not match",
+        ));
+    }
+    let mut salt = [0; 16];
+    let mut nonce = [0; 12];
+    rand::rand_bytes(&mut salt).map_err(openssl_error)?;
+    rand::rand_bytes(&mut nonce).map_err(openssl_error)?;
+    let key = derive_key(&passphrase, &salt)?;
+    let plaintext = serde_json::to_string(api_key).expect("failed to construct json");
+    let mut tag = [0; 16];
+    let ciphertext = symm::encrypt_aead(
+        symm::Cipher::aes_256_gcm(),
+        &key,
+        Some(&nonce),
+        &[],
+        plaintext.as_bytes(),
+        &mut tag,
+    ).map_err(openssl_error)?;
+    Ok(EncryptedApiKey {
+        salt: base64::encode(&salt),
+        nonce: base64::encode(&nonce),
+        tag: base64::encode(&tag),
+        ciphertext: base64::encode(&ciphertext),
+    })
+}
+
+fn decrypt(encrypted: &EncryptedApiKey, prompt: &str) -> std::io::Result<ApiKey> {
+    let passphrase = prompt::prompt_password(prompt)?;
+    let key = derive_key(&passphrase, &decode(&encrypted.salt)?)?;
+    let plaintext = symm::decrypt_aead(
+        symm::Cipher::aes_256_gcm(),
+        &key,
+        Some(&decode(&encrypted.nonce)?),
+        &[],
+        &decode(&encrypted.ciphertext)?,
+        &decode(&encrypted.tag)?,
+    ).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "incorrect passphrase")
+    })?;
+    serde_json::from_slice(&plaintext).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    })
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> std::io::Result<[u8; 32]> {
+    let mut key = [0; 32];
+    pkcs5::pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        KDF_ROUNDS,
+        hash::MessageDigest::sha256(),
+        &mut key,
+    ).map_err(openssl_error)?;
+    Ok(key)
+}
+
+fn decode(string: &str) -> std::io::Result<Vec<u8>> {
+    base64::decode(string).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    })
+}
+
+fn openssl_error(err: openssl::error::ErrorStack) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, err)
+}
+
+fn from_helper(command: &str, url: &url::Url) -> Option<ApiKey> {
+    debug!("Reading Rancher API key from credential helper {}", command);
+    let output = match run_helper(command, "get", &helper_input(url, None)) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("Credential helper failed: {}", e);
+            return None;
+        }
+    };
+    let mut username = None;
+    let mut password = None;
+    for line in output.lines() {
+        if line.starts_with("username=") {
+            username = Some(line["username=".len()..].to_string());
+        } else if line.starts_with("password=") {
+            password = Some(line["password=".len()..].to_string());
+        }
+    }
+    match (username, password) {
+        (Some(u), Some(p)) => Some(ApiKey::new(u, p)),
+        _ => None,
+    }
+}
+
+/// Input in the format of git-credential(1), the API key's public and secret
+/// values stand in for the username and password.
+fn helper_input(url: &url::Url, api_key: Option<&ApiKey>) -> String {
+    let mut input = format!("protocol={}\nhost={}\n", url.scheme(), host_with_port(url));
+    // installs under different paths on the same host have keys of their own
+    let path = url.path().trim_matches('/');
+    if !path.is_empty() {
+        input.push_str(&format!("path={}\n", path));
+    }
+    if let Some(key) = api_key {
+        input.push_str(&format!(
+            "username={}\npassword={}\n",
+            key.public_value,
+            key.secret_value()
+        ));
+    }
+    input.push_str("\n");
+    input
+}
+
+fn run_helper(command: &str, action: &str, input: &str) -> std::io::Result<String> {
+    debug2!("Running {} {}", command, action);
+    let mut child = std::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("{} {}", command, action))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    child.stdin.take().expect("stdin was piped").write_all(
+        input.as_bytes(),
+    )?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{} {} exited with {}", command, action, output.status),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn host_with_port(url: &url::Url) -> String {
+    match (url.host_str(), url.port()) {
+        (Some(h), Some(p)) => format!("{}:{}", h, p),
+        (Some(h), None) => h.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Whether `url` points at `host`, given as `host[:port]` with the port
+/// omitted when it's the protocol default.
+fn same_server(url: &str, host: &str) -> bool {
+    match url::Url::parse(url) {
+        Ok(ref v) => host_with_port(v) == host,
+        Err(_) => false,
+    }
+}
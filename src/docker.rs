@@ -0,0 +1,430 @@
+extern crate serde;
+extern crate serde_json;
+extern crate url;
+
+use std;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+
+const DEFAULT_SOCKET: &'static str = "/var/run/docker.sock";
+const PROJECT_LABEL: &'static str = "com.docker.compose.project";
+const SERVICE_LABEL: &'static str = "com.docker.compose.service";
+
+#[derive(Debug)]
+pub enum Error {
+    Empty,
+    HttpError(u16, String),
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+    UnsupportedHost(String),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::JsonError(err)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Empty => "empty",
+            Error::HttpError(_, ref message) => message,
+            Error::IoError(ref err) => err.description(),
+            Error::JsonError(ref err) => err.description(),
+            Error::UnsupportedHost(_) => "unsupported docker host",
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::IoError(ref err) => Some(err as &StdError),
+            Error::JsonError(ref err) => Some(err as &StdError),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::HttpError(status, ref message) => write!(fmt, "{} ({})", message, status),
+            Error::UnsupportedHost(ref host) => write!(fmt, "unsupported docker host {}", host),
+            _ => self.description().fmt(fmt),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp(String),
+    Unix(std::path::PathBuf),
+}
+
+impl Endpoint {
+    /// `DOCKER_HOST` wins, otherwise localhost means the local daemon's
+    /// socket and anything else is reached over plain tcp.
+    pub fn new(host_name: &str, port: u16) -> Result<Endpoint, Error> {
+        match std::env::var("DOCKER_HOST") {
+            Ok(ref value) if !value.is_empty() => value.parse(),
+            _ if host_name == "localhost" => Ok(Endpoint::Unix(DEFAULT_SOCKET.into())),
+            _ => Ok(Endpoint::Tcp(format!("{}:{}", host_name, port))),
+        }
+    }
+
+    fn connect(&self) -> Result<Stream, Error> {
+        debug2!("Connecting to {}", self);
+        match *self {
+            Endpoint::Tcp(ref addr) => Ok(Stream::Tcp(std::net::TcpStream::connect(addr)?)),
+            Endpoint::Unix(ref path) => Ok(Stream::Unix(
+                std::os::unix::net::UnixStream::connect(path)?,
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match self {
+            &Endpoint::Tcp(ref addr) => write!(fmt, "tcp://{}", addr),
+            &Endpoint::Unix(ref path) => write!(fmt, "unix://{}", path.to_string_lossy()),
+        }
+    }
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("unix://") {
+            Ok(Endpoint::Unix(s["unix://".len()..].into()))
+        } else if s.starts_with("tcp://") {
+            Ok(Endpoint::Tcp(s["tcp://".len()..].trim_right_matches('/').into()))
+        } else {
+            Err(Error::UnsupportedHost(s.into()))
+        }
+    }
+}
+
+pub enum Stream {
+    Tcp(std::net::TcpStream),
+    Unix(std::os::unix::net::UnixStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> std::io::Result<Stream> {
+        match *self {
+            Stream::Tcp(ref s) => s.try_clone().map(Stream::Tcp),
+            Stream::Unix(ref s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: std::net::Shutdown) -> std::io::Result<()> {
+        match *self {
+            Stream::Tcp(ref s) => s.shutdown(how),
+            Stream::Unix(ref s) => s.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut s) => s.flush(),
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Container {
+    id: String,
+    names: Vec<String>,
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match self.names.get(0) {
+            Some(name) => name.trim_left_matches('/').fmt(fmt),
+            None => self.id.fmt(fmt),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerExec {
+    attach_stdin: bool,
+    attach_stdout: bool,
+    attach_stderr: bool,
+    cmd: Vec<String>,
+    env: Vec<String>,
+    tty: bool,
+}
+
+impl ContainerExec {
+    pub fn new(cmd: Vec<String>, env: Vec<String>, tty: bool) -> ContainerExec {
+        ContainerExec {
+            attach_stdin: true,
+            attach_stdout: true,
+            attach_stderr: true,
+            cmd,
+            env,
+            tty,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ExecStart {
+    detach: bool,
+    tty: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Created {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorMessage {
+    message: String,
+}
+
+pub struct Client {
+    endpoint: Endpoint,
+}
+
+impl Client {
+    pub fn new(endpoint: Endpoint) -> Client {
+        Client { endpoint }
+    }
+
+    pub fn executeable_containers(&self, project: &str, service: &str) -> Result<Vec<Container>, Error> {
+        debug!("Connecting to {}", self.endpoint);
+        debug!("Searching for compose project {} service {}", project, service);
+        let mut filters = HashMap::new();
+        filters.insert(
+            "label",
+            vec![
+                format!("{}={}", PROJECT_LABEL, project),
+                format!("{}={}", SERVICE_LABEL, service),
+            ],
+        );
+        filters.insert("status", vec![String::from("running")]);
+        let filters = serde_json::to_string(&filters)?;
+        let path = format!(
+            "/containers/json?filters={}",
+            url::form_urlencoded::byte_serialize(filters.as_bytes()).collect::<String>()
+        );
+        self.get(&path)
+    }
+
+    pub fn create_exec(&self, container: &Container, exec: &ContainerExec) -> Result<String, Error> {
+        let created: Created = self.post(&format!("/containers/{}/exec", container.id), exec)?;
+        Ok(created.id)
+    }
+
+    /// Starts the exec and hands back the hijacked connection, which carries
+    /// raw terminal data when `tty` is set or multiplexed frames otherwise.
+    pub fn start_exec(&self, id: &str, tty: bool) -> Result<Stream, Error> {
+        let path = format!("/exec/{}/start", id);
+        let body = serde_json::to_string(&ExecStart { detach: false, tty })?;
+        debug2!("POST {}", path);
+        let mut stream = self.endpoint.connect()?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: docker\r\nConnection: Upgrade\r\nUpgrade: tcp\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+        // read the head a byte at a time so nothing from the hijacked stream
+        // is lost in a buffer
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte)? == 0 {
+                return Err(Error::Empty);
+            }
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        debug3!("{}", head.trim());
+        let status = parse_status(head.lines().next().unwrap_or(""))?;
+        if status != 101 && status != 200 {
+            return Err(Error::HttpError(status, String::from("failed to start exec")));
+        }
+        Ok(stream)
+    }
+
+    pub fn resize_exec(&self, id: &str, cols: u16, rows: u16) -> Result<(), Error> {
+        let path = format!("/exec/{}/resize?h={}&w={}", id, rows, cols);
+        self.request("POST", &path, None).map(|_| ())
+    }
+
+    fn get<T>(&self, path: &str) -> Result<T, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let body = self.request("GET", path, None)?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    fn post<T, U>(&self, path: &str, body: &T) -> Result<U, Error>
+    where
+        T: serde::Serialize,
+        U: serde::de::DeserializeOwned,
+    {
+        let body = self.request("POST", path, Some(serde_json::to_string(body)?))?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    fn request(&self, method: &str, path: &str, body: Option<String>) -> Result<Vec<u8>, Error> {
+        debug2!("{} {}", method, path);
+        let mut stream = self.endpoint.connect()?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: docker\r\nConnection: close\r\n",
+            method,
+            path
+        )?;
+        match body {
+            Some(ref v) => write!(
+                stream,
+                "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                v.len(),
+                v
+            )?,
+            None => write!(stream, "\r\n")?,
+        };
+        stream.flush()?;
+
+        let mut reader = std::io::BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let status = parse_status(&line)?;
+        let mut headers = HashMap::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                break;
+            }
+            if let Some(i) = trimmed.find(':') {
+                headers.insert(
+                    trimmed[..i].trim().to_lowercase(),
+                    trimmed[i + 1..].trim().to_string(),
+                );
+            }
+        }
+        debug3!("{} {:?}", status, headers);
+
+        let mut body = Vec::new();
+        if headers.get("transfer-encoding").map(|v| v.as_ref()) == Some("chunked") {
+            loop {
+                line.clear();
+                reader.read_line(&mut line)?;
+                let size = usize::from_str_radix(line.trim(), 16).map_err(|_| Error::Empty)?;
+                if size == 0 {
+                    break;
+                }
+                let start = body.len();
+                body.resize(start + size, 0);
+                reader.read_exact(&mut body[start..])?;
+                line.clear();
+                reader.read_line(&mut line)?;
+            }
+        } else {
+            reader.read_to_end(&mut body)?;
+        }
+
+        if status >= 400 {
+            let message = serde_json::from_slice::<ErrorMessage>(&body)
+                .map(|e| e.message)
+                .unwrap_or(String::from("request failed"));
+            return Err(Error::HttpError(status, message));
+        }
+        Ok(body)
+    }
+}
+
+fn parse_status(line: &str) -> Result<u16, Error> {
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or(Error::Empty)
+}
+
+/// Copies the output of a started exec to stdout/stderr, splitting apart the
+/// multiplexed frames Docker uses when no TTY was allocated.
+pub fn forward_output<R: Read>(reader: &mut R, tty: bool) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    let mut stderr = std::io::stderr();
+    let mut buffer = [0; 4096];
+    if tty {
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            stdout.write_all(&buffer[..read])?;
+            stdout.flush()?;
+        }
+    }
+    let mut header = [0; 8];
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut remaining = ((header[4] as usize) << 24) | ((header[5] as usize) << 16) |
+            ((header[6] as usize) << 8) | (header[7] as usize);
+        while remaining > 0 {
+            let len = std::cmp::min(remaining, buffer.len());
+            reader.read_exact(&mut buffer[..len])?;
+            match header[0] {
+                2 => {
+                    stderr.write_all(&buffer[..len])?;
+                    stderr.flush()?;
+                }
+                _ => {
+                    stdout.write_all(&buffer[..len])?;
+                    stdout.flush()?;
+                }
+            };
+            remaining -= len;
+        }
+    }
+}
@@ -0,0 +1,443 @@
+extern crate base64;
+extern crate openssl;
+extern crate serde;
+extern crate serde_json;
+extern crate url;
+
+use std;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::time::Duration;
+
+use self::openssl::ssl::SslStream;
+
+use proxy;
+use tls;
+
+// how long a server may take to respond
+const TIMEOUT_SECS: u64 = 30;
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug)]
+pub enum Error {
+    BadResponse(String),
+    BadUrl(String),
+    // the server closed the connection without responding
+    Closed,
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+    TlsError(tls::Error),
+    TooManyRedirects,
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::JsonError(err)
+    }
+}
+
+impl From<tls::Error> for Error {
+    fn from(err: tls::Error) -> Error {
+        Error::TlsError(err)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::BadResponse(_) => "bad response",
+            Error::BadUrl(_) => "bad URL",
+            Error::Closed => "connection closed",
+            Error::IoError(ref err) => err.description(),
+            Error::JsonError(ref err) => err.description(),
+            Error::TlsError(ref err) => err.description(),
+            Error::TooManyRedirects => "too many redirects",
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::IoError(ref err) => Some(err as &StdError),
+            Error::JsonError(ref err) => Some(err as &StdError),
+            Error::TlsError(ref err) => Some(err as &StdError),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BadResponse(ref what) => write!(fmt, "bad response from the server: {}", what),
+            Error::BadUrl(ref url) => write!(fmt, "bad URL {}", url),
+            Error::Closed => write!(fmt, "the server closed the connection"),
+            Error::IoError(ref err) => err.fmt(fmt),
+            Error::JsonError(ref err) => write!(fmt, "bad response from the server: {}", err),
+            Error::TlsError(ref err) => err.fmt(fmt),
+            Error::TooManyRedirects => self.description().fmt(fmt),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    method: &'static str,
+    url: url::Url,
+    headers: Vec<(&'static str, String)>,
+    body: Option<Vec<u8>>,
+}
+
+impl Request {
+    pub fn new(method: &'static str, url: url::Url) -> Request {
+        Request {
+            method,
+            url,
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn get(url: url::Url) -> Request {
+        Request::new("GET", url)
+    }
+
+    pub fn post(url: url::Url) -> Request {
+        Request::new("POST", url)
+    }
+
+    pub fn delete(url: url::Url) -> Request {
+        Request::new("DELETE", url)
+    }
+
+    pub fn header(mut self, name: &'static str, value: String) -> Request {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn basic_auth(self, user: &str, password: &str) -> Request {
+        let credentials = base64::encode(&format!("{}:{}", user, password));
+        self.header("Authorization", format!("Basic {}", credentials))
+    }
+
+    pub fn json<T: serde::Serialize>(mut self, body: &T) -> Result<Request, Error> {
+        self.body = Some(serde_json::to_vec(body)?);
+        Ok(self.header("Content-Type", String::from("application/json")))
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    fn is_redirect(&self) -> bool {
+        match self.status {
+            301 | 302 | 303 | 307 | 308 => true,
+            _ => false,
+        }
+    }
+}
+
+// a connection to a server, in TLS for https
+enum Stream {
+    Plain(proxy::Connection),
+    Tls(SslStream<proxy::Connection>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// An HTTP/1.1 client connecting through `upstream`, checking the
+/// certificates of https servers as `tls` says on the very connection the
+/// requests are sent on.
+pub struct Client {
+    upstream: proxy::Upstream,
+    tls: tls::Tls,
+    // the connection left open by the last response, and the scheme, host
+    // and port it's to
+    idle: RefCell<Option<(String, BufReader<Stream>)>>,
+}
+
+impl Client {
+    pub fn new(upstream: proxy::Upstream, tls: tls::Tls) -> Client {
+        Client {
+            upstream,
+            tls,
+            idle: RefCell::new(None),
+        }
+    }
+
+    /// Sends `request`, following any redirects.
+    pub fn send(&self, mut request: Request) -> Result<Response, Error> {
+        let mut redirects = 0;
+        loop {
+            let response = self.exchange(&request)?;
+            if !response.is_redirect() {
+                return Ok(response);
+            }
+            let location = match response.headers.get("location") {
+                Some(v) => v,
+                None => return Ok(response),
+            };
+            let location = request.url.join(location).map_err(|_| {
+                Error::BadResponse(format!("bad redirect to {}", location))
+            })?;
+            redirects += 1;
+            if redirects > MAX_REDIRECTS {
+                return Err(Error::TooManyRedirects);
+            }
+            debug2!("Redirected to {}", location);
+            if response.status != 307 && response.status != 308 {
+                request.method = "GET";
+                request.body = None;
+                request.headers.retain(|&(name, _)| name != "Content-Type");
+            }
+            // credentials are only sent to the server they were given for
+            if location.origin() != request.url.origin() {
+                request.headers.retain(|&(name, _)| name != "Authorization" && name != "Cookie");
+            }
+            request.url = location;
+        }
+    }
+
+    // sends `request` on the connection left open by the last response when
+    // it's to the same server, or on a new one
+    fn exchange(&self, request: &Request) -> Result<Response, Error> {
+        let bad_url = || Error::BadUrl(request.url.to_string());
+        let host = request.url.host_str().ok_or_else(&bad_url)?;
+        let port = request.url.port_or_known_default().ok_or_else(&bad_url)?;
+        let server = format!("{}://{}:{}", request.url.scheme(), host, port);
+        let proxy = self.forwarding_proxy(&request.url);
+
+        let idle = self.idle.borrow_mut().take();
+        if let Some((idle_server, mut stream)) = idle {
+            if idle_server == server {
+                match exchange_on(&mut stream, request, proxy) {
+                    Ok((response, keep)) => {
+                        if keep {
+                            *self.idle.borrow_mut() = Some((server, stream));
+                        }
+                        return Ok(response);
+                    }
+                    // it may have been closed while it was idle
+                    Err(Error::Closed) => debug2!("Connection to {} was closed", server),
+                    Err(e) => return Err(e),
+                };
+            }
+        }
+
+        let mut stream = BufReader::new(self.open(&request.url, host, port)?);
+        let (response, keep) = exchange_on(&mut stream, request, proxy)?;
+        if keep {
+            *self.idle.borrow_mut() = Some((server, stream));
+        }
+        Ok(response)
+    }
+
+    // the proxy plain http requests to `url` are sent to, rather than
+    // through a tunnel
+    fn forwarding_proxy(&self, url: &url::Url) -> Option<&url::Url> {
+        match self.upstream {
+            proxy::Upstream::Proxy(ref proxy) if url.scheme() == "http" => Some(proxy),
+            _ => None,
+        }
+    }
+
+    fn open(&self, url: &url::Url, host: &str, port: u16) -> Result<Stream, Error> {
+        let connection = match self.forwarding_proxy(url) {
+            Some(proxy) => proxy::forward(proxy)?,
+            None => self.upstream.connect(host, port)?,
+        };
+        // set once connected, as a proxy command or the host check may be
+        // waiting on the user until then
+        let timeout = Some(Duration::from_secs(TIMEOUT_SECS));
+        match url.scheme() {
+            "https" => {
+                let stream = self.tls.connect(host, port, connection)?;
+                stream.get_ref().set_timeout(timeout)?;
+                Ok(Stream::Tls(stream))
+            }
+            "http" => {
+                connection.set_timeout(timeout)?;
+                Ok(Stream::Plain(connection))
+            }
+            _ => Err(Error::BadUrl(url.to_string())),
+        }
+    }
+}
+
+// sends `request` on `stream` and reads the response, giving whether the
+// connection can be used for the next request
+fn exchange_on(stream: &mut BufReader<Stream>, request: &Request, proxy: Option<&url::Url>) -> Result<(Response, bool), Error> {
+    let head = request_head(request, proxy);
+    let mut line = String::new();
+    let sent = {
+        let writer = stream.get_mut();
+        writer
+            .write_all(head.as_bytes())
+            .and_then(|_| writer.write_all(request.body.as_ref().map_or(&[], |v| &v[..])))
+            .and_then(|_| writer.flush())
+    };
+    match sent.and_then(|_| stream.read_line(&mut line)) {
+        Ok(0) => return Err(Error::Closed),
+        Ok(_) => (),
+        Err(ref e) if is_closed(e) => return Err(Error::Closed),
+        Err(e) => return Err(e.into()),
+    };
+    let (persistent, status, reason) = {
+        let mut words = line.trim().splitn(3, ' ');
+        let persistent = words.next() == Some("HTTP/1.1");
+        let status = words.next().and_then(|s| s.parse().ok());
+        (persistent, status, words.next().unwrap_or("").to_string())
+    };
+    let status: u16 = status.ok_or_else(|| {
+        Error::BadResponse(format!("bad status line {}", line.trim()))
+    })?;
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        stream.read_line(&mut line)?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            break;
+        }
+        if let Some(i) = trimmed.find(':') {
+            headers.insert(
+                trimmed[..i].trim().to_lowercase(),
+                trimmed[i + 1..].trim().to_string(),
+            );
+        }
+    }
+    debug3!("{} {} {:?}", status, reason, headers);
+
+    let mut keep = persistent &&
+        !headers.get("connection").map_or(false, |v| v.eq_ignore_ascii_case("close"));
+    let mut body = Vec::new();
+    let chunked = headers.get("transfer-encoding").map_or(false, |v| {
+        v.to_lowercase().ends_with("chunked")
+    });
+    if request.method == "HEAD" || status / 100 == 1 || status == 204 || status == 304 {
+        // no body
+    } else if chunked {
+        loop {
+            line.clear();
+            stream.read_line(&mut line)?;
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| {
+                Error::BadResponse(format!("bad chunk size {}", size))
+            })?;
+            if size == 0 {
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            stream.read_exact(&mut body[start..])?;
+            line.clear();
+            stream.read_line(&mut line)?;
+        }
+        // any trailers, up to the blank line
+        loop {
+            line.clear();
+            if stream.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+    } else if let Some(length) = headers.get("content-length") {
+        let length = length.parse().map_err(|_| {
+            Error::BadResponse(format!("bad content length {}", length))
+        })?;
+        body.resize(length, 0);
+        stream.read_exact(&mut body)?;
+    } else {
+        // the body ends with the connection
+        stream.read_to_end(&mut body)?;
+        keep = false;
+    }
+
+    let response = Response {
+        status,
+        reason,
+        headers,
+        body,
+    };
+    Ok((response, keep))
+}
+
+// the request line and headers, the request line having the whole URL when
+// it's sent to a proxy
+fn request_head(request: &Request, proxy: Option<&url::Url>) -> String {
+    let url = &request.url;
+    let target = match proxy {
+        Some(_) => &url[..url::Position::AfterQuery],
+        None => &url[url::Position::BeforePath..url::Position::AfterQuery],
+    };
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, target);
+    head.push_str(&format!("Host: {}\r\n", &url[url::Position::BeforeHost..url::Position::AfterPort]));
+    head.push_str(&format!("User-Agent: rsh/{}\r\n", env!("CARGO_PKG_VERSION")));
+    head.push_str("Accept: application/json\r\n");
+    if let Some(v) = proxy.and_then(proxy::authorization) {
+        head.push_str(&format!("Proxy-Authorization: {}\r\n", v));
+    }
+    for &(name, ref value) in &request.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(ref body) = request.body {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    head
+}
+
+// whether `err` is from a connection the server has closed
+fn is_closed(err: &std::io::Error) -> bool {
+    match err.kind() {
+        std::io::ErrorKind::BrokenPipe |
+        std::io::ErrorKind::ConnectionAborted |
+        std::io::ErrorKind::ConnectionReset |
+        std::io::ErrorKind::UnexpectedEof => true,
+        _ => false,
+    }
+}
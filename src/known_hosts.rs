@@ -0,0 +1,52 @@
+use std;
+use std::io::{BufRead, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+/// How the key in a server's certificate compares to the one recorded for it.
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Known,
+    Unknown,
+    /// A different key was recorded, on the given line.
+    Changed(usize),
+}
+
+/// Compares `fingerprint` to the one recorded for `name`, a `host:port`, in
+/// the known hosts file at `path`. Each line of the file is a name and a
+/// fingerprint, with `#` starting a comment.
+pub fn check(path: &std::path::Path, name: &str, fingerprint: &str) -> std::io::Result<Status> {
+    let file = match std::fs::File::open(path) {
+        Ok(v) => v,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Status::Unknown),
+        Err(e) => return Err(e),
+    };
+    for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split('#').next().unwrap_or("").split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(v), Some(recorded)) if v == name => {
+                debug2!("Found {} in {}:{}", name, path.to_string_lossy(), i + 1);
+                return Ok(if recorded == fingerprint {
+                    Status::Known
+                } else {
+                    Status::Changed(i + 1)
+                });
+            }
+            _ => (),
+        };
+    }
+    Ok(Status::Unknown)
+}
+
+/// Records `fingerprint` for `name` at the end of the known hosts file.
+pub fn add(path: &std::path::Path, name: &str, fingerprint: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{} {}", name, fingerprint)
+}
@@ -0,0 +1,727 @@
+extern crate base64;
+extern crate native_tls;
+extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate url;
+extern crate websocket;
+
+use std;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::Read;
+
+pub const PROTOCOL: &'static str = "v4.channel.k8s.io";
+
+pub const STDIN_CHANNEL: u8 = 0;
+pub const STDOUT_CHANNEL: u8 = 1;
+pub const STDERR_CHANNEL: u8 = 2;
+pub const ERROR_CHANNEL: u8 = 3;
+pub const RESIZE_CHANNEL: u8 = 4;
+
+#[derive(Debug)]
+pub enum Error {
+    Empty,
+    HttpError(reqwest::Error),
+    IoError(std::io::Error),
+    JsonError(serde_json::Error),
+    KubeconfigError(String),
+    TlsError(native_tls::Error),
+    Unauthorized,
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        Error::HttpError(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::JsonError(err)
+    }
+}
+
+impl From<native_tls::Error> for Error {
+    fn from(err: native_tls::Error) -> Error {
+        Error::TlsError(err)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Empty => "empty",
+            Error::HttpError(ref err) => err.description(),
+            Error::IoError(ref err) => err.description(),
+            Error::JsonError(ref err) => err.description(),
+            Error::KubeconfigError(ref message) => message,
+            Error::TlsError(ref err) => err.description(),
+            Error::Unauthorized => "unauthorized",
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::HttpError(ref err) => Some(err as &StdError),
+            Error::IoError(ref err) => Some(err as &StdError),
+            Error::JsonError(ref err) => Some(err as &StdError),
+            Error::TlsError(ref err) => Some(err as &StdError),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        self.description().fmt(fmt)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Kubeconfig {
+    #[serde(default)]
+    clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    contexts: Vec<NamedContext>,
+    #[serde(default)]
+    users: Vec<NamedAuthInfo>,
+    current_context: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedCluster {
+    name: String,
+    cluster: Cluster,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Cluster {
+    server: String,
+    certificate_authority: Option<String>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedContext {
+    name: String,
+    context: Context,
+}
+
+#[derive(Debug, Deserialize)]
+struct Context {
+    cluster: String,
+    user: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedAuthInfo {
+    name: String,
+    user: AuthInfo,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct AuthInfo {
+    client_certificate: Option<String>,
+    client_certificate_data: Option<String>,
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl Kubeconfig {
+    /// Every file in `KUBECONFIG`, or `~/.kube/config` when it's unset.
+    fn paths() -> Vec<std::path::PathBuf> {
+        match std::env::var("KUBECONFIG") {
+            Ok(ref v) if !v.is_empty() => v.split(':').filter(|p| !p.is_empty()).map(Into::into).collect(),
+            _ => {
+                let mut path = std::env::home_dir().unwrap_or(std::path::PathBuf::from("/"));
+                path.push(".kube");
+                path.push("config");
+                vec![path]
+            }
+        }
+    }
+
+    /// kubeconfig files are normally YAML, which we can only read when they
+    /// happen to be JSON, so otherwise ask kubectl to convert them for us.
+    /// The files are merged as kubectl does, the first to name a cluster,
+    /// context, or user, or to set the current context, winning.
+    fn load() -> Result<Kubeconfig, Error> {
+        let mut merged = Kubeconfig::default();
+        for path in Kubeconfig::paths() {
+            debug!("Reading kubeconfig {}", path.to_string_lossy());
+            let mut string = String::new();
+            // missing files are skipped, as kubectl does
+            match std::fs::File::open(&path) {
+                Ok(mut file) => file.read_to_string(&mut string)?,
+                Err(_) => continue,
+            };
+            match serde_json::from_str(&string) {
+                Ok(v) => merged.merge(v),
+                Err(_) => return Kubeconfig::view(&path),
+            };
+        }
+        Ok(merged)
+    }
+
+    // the merged kubeconfig as kubectl gives it, `path` being a file that
+    // couldn't be read
+    fn view(path: &std::path::Path) -> Result<Kubeconfig, Error> {
+        debug!("Reading kubeconfig with kubectl config view");
+        let output = std::process::Command::new("kubectl")
+            .args(&["config", "view", "--raw", "-o", "json"])
+            .output()
+            .map_err(|_| {
+                Error::KubeconfigError(format!(
+                    "couldn't parse {}, and kubectl is unavailable",
+                    path.to_string_lossy()
+                ))
+            })?;
+        if !output.status.success() {
+            return Err(Error::KubeconfigError(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+
+    fn merge(&mut self, other: Kubeconfig) {
+        for cluster in other.clusters {
+            if !self.clusters.iter().any(|c| c.name == cluster.name) {
+                self.clusters.push(cluster);
+            }
+        }
+        for context in other.contexts {
+            if !self.contexts.iter().any(|c| c.name == context.name) {
+                self.contexts.push(context);
+            }
+        }
+        for user in other.users {
+            if !self.users.iter().any(|u| u.name == user.name) {
+                self.users.push(user);
+            }
+        }
+        if self.current_context.is_none() {
+            self.current_context = other.current_context;
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Deployment {
+    spec: DeploymentSpec,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeploymentSpec {
+    selector: LabelSelector,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LabelSelector {
+    #[serde(default)]
+    match_labels: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PodList {
+    items: Vec<Pod>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Pod {
+    metadata: Metadata,
+    spec: PodSpec,
+    status: PodStatus,
+}
+
+impl fmt::Display for Pod {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        self.metadata.name.fmt(fmt)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PodSpec {
+    containers: Vec<PodContainer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PodContainer {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PodStatus {
+    phase: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Status {
+    status: String,
+    message: Option<String>,
+}
+
+/// What arrived in a message on the exec websocket.
+#[derive(Debug, PartialEq)]
+pub enum Output<'a> {
+    Stdout(&'a [u8]),
+    Stderr(&'a [u8]),
+    /// The command failed, or couldn't be started, with this message.
+    Error(String),
+    Ignored,
+}
+
+/// Splits a message from the exec websocket into its channel and data.
+pub fn read_channel(data: &[u8]) -> Output {
+    match data.split_first() {
+        Some((&STDOUT_CHANNEL, bytes)) => Output::Stdout(bytes),
+        Some((&STDERR_CHANNEL, bytes)) => Output::Stderr(bytes),
+        Some((&ERROR_CHANNEL, bytes)) => match serde_json::from_slice::<Status>(bytes) {
+            Ok(Status {
+                ref status,
+                message: Some(ref message),
+            }) if status != "Success" => Output::Error(message.to_owned()),
+            _ => Output::Ignored,
+        },
+        _ => Output::Ignored,
+    }
+}
+
+/// A message sending `data` on `channel` of the exec websocket.
+pub fn write_channel(channel: u8, data: &[u8]) -> Vec<u8> {
+    let mut message = vec![channel];
+    message.extend(data);
+    message
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TerminalSize {
+    width: u16,
+    height: u16,
+}
+
+impl TerminalSize {
+    pub fn new(width: u16, height: u16) -> TerminalSize {
+        TerminalSize { width, height }
+    }
+}
+
+enum Credentials {
+    Basic(String, String),
+    Bearer(String),
+    None,
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    server: url::Url,
+    credentials: Credentials,
+    certificate_authority: Option<Vec<u8>>,
+}
+
+impl Client {
+    pub fn from_kubeconfig(context_name: &str) -> Result<Client, Error> {
+        let config = Kubeconfig::load()?;
+        let context_name = match (context_name, config.current_context) {
+            ("current", Some(ref v)) => v.to_owned(),
+            (v, _) => v.to_owned(),
+        };
+        let context = config
+            .contexts
+            .into_iter()
+            .find(|c| c.name == context_name)
+            .ok_or(Error::KubeconfigError(format!("no context named {}", context_name)))?
+            .context;
+        let cluster = config
+            .clusters
+            .into_iter()
+            .find(|c| c.name == context.cluster)
+            .ok_or(Error::KubeconfigError(format!("no cluster named {}", context.cluster)))?
+            .cluster;
+        let auth_info = config
+            .users
+            .into_iter()
+            .find(|u| u.name == context.user)
+            .map(|u| u.user)
+            .unwrap_or_default();
+
+        let server = url::Url::parse(&cluster.server).map_err(|_| {
+            Error::KubeconfigError(format!("bad server {}", cluster.server))
+        })?;
+
+        let certificate_authority = match (cluster.certificate_authority_data, cluster.certificate_authority) {
+            (Some(ref data), _) => Some(base64::decode(data).map_err(|_| {
+                Error::KubeconfigError(String::from("bad certificate-authority-data"))
+            })?),
+            (None, Some(ref path)) => {
+                let mut buffer = Vec::new();
+                std::fs::File::open(path)?.read_to_end(&mut buffer)?;
+                Some(buffer)
+            }
+            (None, None) => None,
+        };
+
+        // neither reqwest nor the websocket's TLS connector can accept any
+        // certificate, only skip checking the host name
+        if cluster.insecure_skip_tls_verify {
+            return Err(Error::KubeconfigError(
+                String::from("insecure-skip-tls-verify is not supported"),
+            ));
+        }
+        if auth_info.client_certificate.is_some() || auth_info.client_certificate_data.is_some() {
+            return Err(Error::KubeconfigError(
+                String::from("client certificate authentication is not supported"),
+            ));
+        }
+        let credentials = match auth_info {
+            AuthInfo { token: Some(token), .. } => Credentials::Bearer(token),
+            AuthInfo { token_file: Some(ref path), .. } => {
+                let mut token = String::new();
+                std::fs::File::open(path)?.read_to_string(&mut token)?;
+                Credentials::Bearer(token.trim().to_string())
+            }
+            AuthInfo {
+                username: Some(username),
+                password: Some(password),
+                ..
+            } => Credentials::Basic(username, password),
+            _ => Credentials::None,
+        };
+
+        let mut builder = reqwest::Client::builder();
+        if let Some(ref pem) = certificate_authority {
+            builder.add_root_certificate(reqwest::Certificate::from_pem(pem)?);
+        }
+
+        Ok(Client {
+            http: builder.build()?,
+            server,
+            credentials,
+            certificate_authority,
+        })
+    }
+
+    pub fn executeable_pods(&self, namespace: &str, deployment: &str) -> Result<Vec<Pod>, Error> {
+        debug!("Connecting to {}", self.server);
+        debug!("Searching for deployment {}", deployment);
+        let deployment_url = self.api_url(&format!(
+            "/apis/apps/v1/namespaces/{}/deployments/{}",
+            namespace,
+            deployment
+        ));
+        let deployment: Deployment = self.get(&deployment_url)?;
+
+        debug!("Searching for running pods");
+        let mut selector: Vec<_> = deployment
+            .spec
+            .selector
+            .match_labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        selector.sort();
+        let mut pods_url = self.api_url(&format!("/api/v1/namespaces/{}/pods", namespace));
+        pods_url.query_pairs_mut().append_pair(
+            "labelSelector",
+            &selector.join(","),
+        );
+        let pods: PodList = self.get(&pods_url)?;
+        Ok(
+            pods.items
+                .into_iter()
+                .filter(|p| p.status.phase == "Running")
+                .collect(),
+        )
+    }
+
+    pub fn exec_url(&self, namespace: &str, pod: &Pod, command: &[String], tty: bool) -> url::Url {
+        let mut exec_url = self.api_url(&format!(
+            "/api/v1/namespaces/{}/pods/{}/exec",
+            namespace,
+            pod.metadata.name
+        ));
+        let scheme = if self.server.scheme() == "http" {
+            "ws"
+        } else {
+            "wss"
+        };
+        exec_url.set_scheme(scheme).expect("ws scheme rejected");
+        {
+            let mut query = exec_url.query_pairs_mut();
+            if let Some(container) = pod.spec.containers.get(0) {
+                query.append_pair("container", &container.name);
+            }
+            query.append_pair("stdin", "true");
+            query.append_pair("stdout", "true");
+            query.append_pair("stderr", if tty { "false" } else { "true" });
+            query.append_pair("tty", if tty { "true" } else { "false" });
+            for part in command {
+                query.append_pair("command", part);
+            }
+        }
+        exec_url
+    }
+
+    pub fn websocket_headers(&self) -> websocket::header::Headers {
+        let mut headers = websocket::header::Headers::new();
+        match self.credentials {
+            Credentials::Basic(ref username, ref password) => {
+                let encoded = base64::encode(&format!("{}:{}", username, password));
+                headers.set_raw("Authorization", vec![format!("Basic {}", encoded).into_bytes()]);
+            }
+            Credentials::Bearer(ref token) => {
+                headers.set_raw("Authorization", vec![format!("Bearer {}", token).into_bytes()]);
+            }
+            Credentials::None => (),
+        };
+        headers
+    }
+
+    pub fn tls_connector(&self) -> Result<Option<native_tls::TlsConnector>, Error> {
+        match self.certificate_authority {
+            Some(ref pem) => {
+                let mut builder = native_tls::TlsConnector::builder()?;
+                builder.add_root_certificate(native_tls::Certificate::from_pem(pem)?)?;
+                Ok(Some(builder.build()?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // the server may be proxied under a path, as Rancher does with
+    // /k8s/clusters/<id>
+    fn api_url(&self, path: &str) -> url::Url {
+        let mut url = self.server.clone();
+        let base_path = self.server.path().trim_right_matches('/').to_string();
+        url.set_path(&format!("{}{}", base_path, path));
+        url
+    }
+
+    fn get<T>(&self, url: &url::Url) -> Result<T, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        debug2!("GET {}", url);
+        let mut request = self.http.get(url.clone());
+        match self.credentials {
+            Credentials::Basic(ref username, ref password) => {
+                request.basic_auth(username.clone(), Some(password.clone()));
+            }
+            Credentials::Bearer(ref token) => {
+                request.header(reqwest::header::Authorization(
+                    reqwest::header::Bearer { token: token.clone() },
+                ));
+            }
+            Credentials::None => (),
+        };
+        let mut response = request.send()?;
+        debug3!("{:?}", response);
+        if response.status() == reqwest::StatusCode::Unauthorized ||
+            response.status() == reqwest::StatusCode::Forbidden
+        {
+            return Err(Error::Unauthorized);
+        };
+        if response.status() == reqwest::StatusCode::NotFound {
+            return Err(Error::Empty);
+        };
+
+        Ok(response.json()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    // answers requests for paths under `base_path` with the given bodies,
+    // recording each request's line and Authorization header
+    fn fake_api_server(
+        base_path: &str,
+        routes: Vec<(&'static str, &'static str)>,
+    ) -> (url::Url, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = url::Url::parse(&format!(
+            "http://{}{}",
+            listener.local_addr().unwrap(),
+            base_path
+        )).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        std::thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut authorization = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+                if line.to_lowercase().starts_with("authorization:") {
+                    authorization = line[14..].trim().to_string();
+                }
+            }
+            let target = request_line.split_whitespace().nth(1).unwrap_or("").to_string();
+            recorded.lock().unwrap().push(format!("{} {}", target, authorization));
+            let response = match routes.iter().find(|r| target.split('?').next() == Some(r.0)) {
+                Some(&(_, body)) => format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                ),
+                None => String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        (server, requests)
+    }
+
+    fn client(server: url::Url) -> Client {
+        Client {
+            http: reqwest::Client::new(),
+            server,
+            credentials: Credentials::Bearer(String::from("secret")),
+            certificate_authority: None,
+        }
+    }
+
+    fn pod(name: &str, phase: &str) -> Pod {
+        Pod {
+            metadata: Metadata { name: name.to_string() },
+            spec: PodSpec { containers: vec![PodContainer { name: String::from("web") }] },
+            status: PodStatus { phase: phase.to_string() },
+        }
+    }
+
+    #[test]
+    fn finds_running_pods_of_deployment() {
+        let (server, requests) = fake_api_server(
+            "/k8s/clusters/c-1",
+            vec![
+                (
+                    "/k8s/clusters/c-1/apis/apps/v1/namespaces/production/deployments/api",
+                    r#"{"spec": {"selector": {"matchLabels": {"tier": "web", "app": "api"}}}}"#,
+                ),
+                (
+                    "/k8s/clusters/c-1/api/v1/namespaces/production/pods",
+                    r#"{"items": [
+                        {"metadata": {"name": "api-1"}, "spec": {"containers": [{"name": "web"}]},
+                         "status": {"phase": "Running"}},
+                        {"metadata": {"name": "api-2"}, "spec": {"containers": [{"name": "web"}]},
+                         "status": {"phase": "Pending"}}
+                    ]}"#,
+                ),
+            ],
+        );
+        let pods = client(server).executeable_pods("production", "api").unwrap();
+        let names: Vec<_> = pods.iter().map(|p| p.to_string()).collect();
+        assert_eq!(names, vec!["api-1"]);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "/k8s/clusters/c-1/apis/apps/v1/namespaces/production/deployments/api Bearer secret",
+                "/k8s/clusters/c-1/api/v1/namespaces/production/pods?labelSelector=app%3Dapi%2Ctier%3Dweb \
+                 Bearer secret",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_deployment_is_empty() {
+        let (server, _) = fake_api_server("", vec![]);
+        match client(server).executeable_pods("production", "api") {
+            Err(Error::Empty) => (),
+            v => panic!("expected Empty, got {:?}", v.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn exec_url_keeps_base_path() {
+        let https = client(url::Url::parse("https://rancher.example.com/k8s/clusters/c-1").unwrap());
+        let command = vec![String::from("/bin/sh"), String::from("-c"), String::from("ls -l")];
+        assert_eq!(
+            https.exec_url("production", &pod("api-1", "Running"), &command, true).as_str(),
+            "wss://rancher.example.com/k8s/clusters/c-1/api/v1/namespaces/production/pods/api-1/exec\
+             ?container=web&stdin=true&stdout=true&stderr=false&tty=true\
+             &command=%2Fbin%2Fsh&command=-c&command=ls+-l"
+        );
+        let http = client(url::Url::parse("http://localhost:8080/").unwrap());
+        assert_eq!(
+            http.exec_url("default", &pod("api-1", "Running"), &command[..1], false).as_str(),
+            "ws://localhost:8080/api/v1/namespaces/default/pods/api-1/exec\
+             ?container=web&stdin=true&stdout=true&stderr=true&tty=false&command=%2Fbin%2Fsh"
+        );
+    }
+
+    #[test]
+    fn merges_kubeconfigs_first_wins() {
+        let mut merged: Kubeconfig = serde_json::from_str(
+            r#"{"clusters": [{"name": "eks", "cluster": {"server": "https://first"}}],
+                "contexts": [{"name": "arn:aws:eks:us-east-1:1:cluster/x",
+                              "context": {"cluster": "eks", "user": "admin"}}]}"#,
+        ).unwrap();
+        merged.merge(serde_json::from_str(
+            r#"{"clusters": [{"name": "eks", "cluster": {"server": "https://second"}},
+                             {"name": "local", "cluster": {"server": "http://localhost:8080"}}],
+                "contexts": [{"name": "local", "context": {"cluster": "local", "user": "dev"}}],
+                "current-context": "local"}"#,
+        ).unwrap());
+        let clusters: Vec<_> = merged.clusters.iter().map(|c| c.cluster.server.as_str()).collect();
+        assert_eq!(clusters, vec!["https://first", "http://localhost:8080"]);
+        let contexts: Vec<_> = merged.contexts.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(contexts, vec!["arn:aws:eks:us-east-1:1:cluster/x", "local"]);
+        assert_eq!(merged.current_context, Some(String::from("local")));
+    }
+
+    #[test]
+    fn reads_channels() {
+        assert_eq!(read_channel(b"\x01out"), Output::Stdout(b"out"));
+        assert_eq!(read_channel(b"\x02err"), Output::Stderr(b"err"));
+        assert_eq!(
+            read_channel(b"\x03{\"status\": \"Failure\", \"message\": \"command terminated\"}"),
+            Output::Error(String::from("command terminated"))
+        );
+        assert_eq!(read_channel(b"\x03{\"status\": \"Success\"}"), Output::Ignored);
+        assert_eq!(read_channel(b"\x03not json"), Output::Ignored);
+        assert_eq!(read_channel(b""), Output::Ignored);
+    }
+
+    #[test]
+    fn writes_channels() {
+        assert_eq!(write_channel(STDIN_CHANNEL, b"ls\n"), b"\x00ls\n".to_vec());
+        let size = serde_json::to_vec(&TerminalSize::new(80, 24)).unwrap();
+        assert_eq!(
+            write_channel(RESIZE_CHANNEL, &size),
+            b"\x04{\"Width\":80,\"Height\":24}".to_vec()
+        );
+    }
+}
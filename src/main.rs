@@ -6,7 +6,6 @@ extern crate lazy_static;
 #[macro_use]
 extern crate nom;
 extern crate nix;
-extern crate rpassword;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
@@ -28,14 +27,24 @@ use termion::raw::IntoRawMode;
 mod log;
 
 mod and_select;
+mod cache;
+mod completion;
 mod config;
+mod control;
+mod credentials;
+mod docker;
 mod escape;
+mod http;
+mod known_hosts;
+mod kubernetes;
 mod options;
 mod pattern;
 mod prompt;
+mod proxy;
 mod rancher;
+mod tls;
 
-use prompt::prompt_with_default;
+use prompt::{prompt_password, prompt_with_default};
 use rancher::{ContainerExec, HostAccess};
 
 const NAME: &'static str = env!("CARGO_PKG_NAME");
@@ -46,14 +55,35 @@ enum ProgramStatus {
     SuccessWithHelp,
     Failure,
     FailureWithHelp,
+    Unauthorized,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Complete,
+    Completion,
+    Connect,
+    Find,
+    Keys,
+    Ls,
+    Login,
+    Logout,
+}
+
+// sysexits(3) EX_NOPERM, lets scripts tell a missing or revoked API key apart
+const UNAUTHORIZED_EXIT_CODE: i32 = 77;
+
+// seconds completions are cached for, keeping completion quick while still
+// picking up new stacks and services
+const COMPLETION_CACHE_TTL: u64 = 300;
+
 fn main() {
     let mut opts = getopts::Options::new();
     opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
     opts.optflag("h", "help", "Print this message and exit");
     opts.optflag("V", "version", "Display the version number and exit");
 
+    opts.optflag("B", "", "Batch mode, fail rather than prompting for input");
     opts.optopt(
         "E",
         "",
@@ -76,6 +106,18 @@ fn main() {
     );
     opts.optmulti("o", "", "Set an option by name", "OPTION");
     opts.optopt("p", "", "Port to connect to on the remote host", "PORT");
+    opts.optflag(
+        "",
+        "connect",
+        "With find, connect to the service when it is the only match",
+    );
+    opts.optflag("", "json", "With ls, print the listing as JSON");
+    opts.optopt(
+        "",
+        "prune",
+        "With keys, revoke API keys not in use that are inactive or older than DAYS",
+        "DAYS",
+    );
     opts.optflag("q", "", "Quiet mode");
     opts.optflag("T", "", "Disable pseudo-terminal allocation");
     opts.optflagmulti("t", "", "Force pseudo-terminal allocation");
@@ -84,6 +126,20 @@ fn main() {
     let mut args: Vec<String> = std::env::args().collect();
     let program = args.remove(0);
 
+    let command = match args.get(0).map(String::as_ref) {
+        Some("__complete") => Command::Complete,
+        Some("completion") => Command::Completion,
+        Some("find") => Command::Find,
+        Some("keys") => Command::Keys,
+        Some("ls") => Command::Ls,
+        Some("login") => Command::Login,
+        Some("logout") => Command::Logout,
+        _ => Command::Connect,
+    };
+    if command != Command::Connect {
+        args.remove(0);
+    }
+
     let matches = match opts.parse(args) {
         Err(e) => {
             eprint!("{}\n{}", e, opts.short_usage(&program));
@@ -92,13 +148,32 @@ fn main() {
         Ok(matches) => matches,
     };
 
-    match run(matches) {
+    if command == Command::Completion {
+        match matches.free.get(0).map(|v| v.parse()) {
+            Some(Ok(shell)) => print!("{}", completion::script(shell, &opts)),
+            Some(Err(e)) => {
+                eprintln!("{}.", e);
+                std::process::exit(1);
+            }
+            None => {
+                eprint!("{}", opts.short_usage(&program));
+                std::process::exit(1);
+            }
+        };
+        return;
+    }
+
+    match run(command, matches) {
         ProgramStatus::Success => (),
         ProgramStatus::SuccessWithHelp => {
             print!(
                 "{}",
                 opts.usage(&format!(
-                    "Usage: {} [opts] [protocol://][user@]host[:port][[/env]/stack]/service [cmd]",
+                    "Usage: {0} [opts] [protocol://][user@]host[:port][[/env]/stack]/service [cmd]\n       \
+                     {0} find [opts] [protocol://][user@]host[:port] pattern [cmd]\n       \
+                     {0} ls [opts] [protocol://][user@]host[:port][/env[/stack[/service]]]\n       \
+                     {0} login|logout|keys [opts] [protocol://][user@]host[:port]\n       \
+                     {0} completion bash|zsh|fish",
                     program
                 ))
             )
@@ -108,11 +183,17 @@ fn main() {
             eprint!("{}", opts.short_usage(&program));
             std::process::exit(1);
         }
+        ProgramStatus::Unauthorized => std::process::exit(UNAUTHORIZED_EXIT_CODE),
     };
 }
 
-fn run(matches: getopts::Matches) -> ProgramStatus {
-    if matches.opt_present("q") || matches.free.len() > 1 {
+fn run(command: Command, matches: getopts::Matches) -> ProgramStatus {
+    // find takes a pattern after the host, the remote command follows that
+    let command_start = if command == Command::Find { 2 } else { 1 };
+
+    if matches.opt_present("q") || command == Command::Complete ||
+        matches.free.len() > command_start
+    {
         log::set_level(options::LogLevel::Quiet);
     }
 
@@ -149,14 +230,28 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
         return ProgramStatus::SuccessWithHelp;
     }
 
-    let host = match matches.free.get(0) {
+    let mut host = match matches.free.get(0) {
         Some(v) => v.clone(),
         None => return ProgramStatus::FailureWithHelp,
     };
 
+    // when completing, the segment being typed is split off and the rest
+    // names the host and path to list
+    let completing = if command == Command::Complete {
+        let split = completion::split_word(&host).map(|(prefix, partial)| {
+            (prefix.to_string(), partial.to_string())
+        });
+        if let Some((ref prefix, _)) = split {
+            host = prefix.trim_right_matches('/').to_string();
+        }
+        split
+    } else {
+        None
+    };
+
     verbose!("{} {}", NAME, VERSION);
 
-    std::fs::create_dir_all(config::user_config_dir()).expect("couldn't create config dir");
+    config::create_user_config_dir().expect("couldn't create config dir");
 
     let config_paths = match matches.opt_str("F").map(std::path::PathBuf::from) {
         Some(val) => vec![val],
@@ -185,10 +280,16 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
                 return ProgramStatus::Failure;
             }
         };
+        // only a command line option can turn off the check of config files
+        let strict_modes = acc.strict_modes(&host).unwrap_or(true);
         for path in config_paths {
             debug!("Reading configuration data {}", path.to_string_lossy());
-            match config::open_config(&path) {
+            match config::open_config(&path, strict_modes) {
                 Ok(v) => acc = acc.append(v),
+                Err(config::Error::BadPermissions) => {
+                    fatal!("Bad owner or permissions on {}", path.to_string_lossy());
+                    return ProgramStatus::Failure;
+                }
                 Err(config::Error::OptionError(key, value)) => {
                     fatal!(
                         "{}: Bad configuration option: \"{}\" for {}.",
@@ -223,6 +324,50 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
         log::set_level(value);
     }
 
+    if command == Command::Complete && completing.is_none() {
+        complete_host(&host, &config);
+        return ProgramStatus::Success;
+    }
+
+    // the path Rancher is served under, from the server's URL
+    let mut base_path = String::new();
+
+    let server = match server_name(&host, &config) {
+        Some((user, name, path)) => {
+            if name.is_empty() {
+                fatal!("No server given for {} and no DefaultServer is set.", host);
+                return ProgramStatus::Failure;
+            }
+            let server = match config.server(&name).or_else(
+                || credentials::rancher_cli_server(&name),
+            ) {
+                Some(v) => v,
+                None => {
+                    fatal!("Unknown server {}.", name);
+                    return ProgramStatus::Failure;
+                }
+            };
+            match server.url {
+                Some(ref v) => {
+                    debug!("Using server {} at {}", name, v);
+                    let mut url = v.clone();
+                    if let Some(ref value) = user {
+                        url.set_username(value).expect("server URL can't have a user");
+                    }
+                    base_path = url.path().trim_right_matches('/').to_string();
+                    url.set_path("");
+                    host = format!("{}/{}", url.as_str().trim_right_matches('/'), path);
+                }
+                None => {
+                    fatal!("No URL for server {}.", name);
+                    return ProgramStatus::Failure;
+                }
+            };
+            Some(server)
+        }
+        None => None,
+    };
+
     let url = match if !host.contains("://") {
         let protocol = config.protocol(&host).unwrap_or(
             options::Protocol::default(),
@@ -243,6 +388,10 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
         return ProgramStatus::FailureWithHelp;
     };
 
+    // ls and completion take the path from the environment down, and list
+    // what's below it
+    let mut listing = Vec::new();
+
     let (environment, stack, service) = {
         let mut path_segments = url.path_segments()
             .expect("cannot-be-a-base URL bypassed check?")
@@ -258,6 +407,12 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
         };
 
         match (first, second, third) {
+            (a, b, c) if command == Command::Ls || command == Command::Complete => {
+                listing.extend(
+                    a.into_iter().chain(b).chain(c).filter(|s| !s.is_empty()),
+                );
+                (None, None, None)
+            }
             (None, None, None) => (None, None, None),
             (Some(ref a), None, None) if a.is_empty() => (None, None, None),
             (a @ Some(_), None, None) => (None, None, a),
@@ -269,6 +424,9 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
 
     let mut option_builder = options::OptionsBuilder::default();
 
+    // managing API keys only needs the server
+    option_builder.server_only(command != Command::Connect);
+
     if let Some(ref value) = environment {
         option_builder.token('e', value.to_owned());
     }
@@ -284,8 +442,10 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
     option_builder.log_level(log::level());
 
     option_builder.protocol(match url.scheme() {
+        "docker" => options::Protocol::Docker,
         "http" => options::Protocol::Http,
         "https" => options::Protocol::Https,
+        "k8s" | "kubernetes" => options::Protocol::Kubernetes,
         _ => {
             verbose!("Unsupported protocol.");
             return ProgramStatus::FailureWithHelp;
@@ -302,7 +462,9 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
         "cannot-be-a-base URL bypassed check?",
     );
     option_builder.token('h', url_host.to_string());
-    option_builder.host_name(config.host_name(&host).unwrap_or(url_host.to_string()));
+    option_builder.host_name(config.host_name(&host).unwrap_or(
+        format!("{}{}", url_host, base_path),
+    ));
 
     if let Some(value) = url.port() {
         option_builder.port(value);
@@ -319,7 +481,10 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
         option_builder.port(value);
     }
 
-    if let Some(value) = config.environment(&host).or(environment) {
+    if let Some(value) = config.environment(&host).or(environment).or_else(|| {
+        server.as_ref().and_then(|s| s.environment.to_owned())
+    })
+    {
         option_builder.environment(value.into());
     }
 
@@ -331,10 +496,96 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
         option_builder.service(value.into());
     }
 
+    if let Some(value) = server.as_ref().and_then(|s| s.auth_provider).or_else(|| {
+        config.auth_provider(&host)
+    })
+    {
+        option_builder.auth_provider(value);
+    }
+
+    // completion mustn't stop to prompt for a password
+    if matches.opt_present("B") || command == Command::Complete {
+        option_builder.batch_mode(true);
+    } else if let Some(value) = config.batch_mode(&host) {
+        option_builder.batch_mode(value);
+    }
+
+    if let Some(value) = config.ca_certificate(&host) {
+        option_builder.ca_certificate(value);
+    }
+
+    if let Some(value) = config.cache_ttl(&host) {
+        option_builder.cache_ttl(value);
+    }
+
+    if let Some(value) = config.check_host_name(&host) {
+        option_builder.check_host_name(value);
+    }
+
+    if let Some(value) = config.client_certificate(&host) {
+        option_builder.client_certificate(value);
+    }
+
+    if let Some(value) = config.client_key(&host) {
+        option_builder.client_key(value);
+    }
+
     if let Some(value) = config.container(&host) {
         option_builder.container(value.into());
     }
 
+    if let Some(value) = config.context(&host) {
+        option_builder.context(value);
+    }
+
+    if let Some(value) = config.control_master(&host) {
+        option_builder.control_master(value);
+    }
+
+    if let Some(value) = config.control_path(&host) {
+        option_builder.control_path(value);
+    }
+
+    if let Some(value) = config.control_persist(&host) {
+        option_builder.control_persist(value);
+    }
+
+    if let Some(value) = config.credential_helper(&host) {
+        option_builder.credential_helper(value);
+    }
+
+    if let Some(value) = config.encrypt_keys(&host) {
+        option_builder.encrypt_keys(value);
+    }
+
+    if let Some(value) = config.strict_host_key_checking(&host) {
+        option_builder.strict_host_key_checking(value);
+    }
+
+    if let Some(value) = config.strict_modes(&host) {
+        option_builder.strict_modes(value);
+    }
+
+    if let Some(value) = config.websocket_host(&host) {
+        option_builder.websocket_host(value);
+    }
+
+    if let Some(value) = config.websocket_protocol(&host) {
+        option_builder.websocket_protocol(value);
+    }
+
+    if let Some(value) = config.number_of_password_prompts(&host) {
+        option_builder.number_of_password_prompts(value);
+    }
+
+    if let Some(value) = config.proxy_command(&host) {
+        option_builder.proxy_command(value);
+    }
+
+    if let Some(value) = config.proxy_url(&host) {
+        option_builder.proxy_url(value);
+    }
+
     if let Some(escape_str) = matches.opt_str("e") {
         if escape_str != "none" {
             match escape_str.parse::<char>() {
@@ -363,8 +614,8 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
         option_builder.send_env(pattern);
     }
 
-    if matches.free.len() > 1 {
-        let vec: Vec<_> = matches.free[1..]
+    if matches.free.len() > command_start {
+        let vec: Vec<_> = matches.free[command_start..]
             .iter()
             .map(|s| shell_escape::escape(s.clone().into()))
             .collect();
@@ -375,9 +626,13 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
 
     let options = match option_builder.build() {
         Ok(v) => v,
-        Err(options::BuildError::MissingEnvironment) => {
-            verbose!("Missing environment.");
-            return ProgramStatus::FailureWithHelp;
+        Err(options::BuildError::BadProxyUrl(url)) => {
+            fatal!("Bad proxy URL '{}'.", url);
+            return ProgramStatus::Failure;
+        }
+        Err(options::BuildError::BadWebsocketHost(host)) => {
+            fatal!("Bad websocket host '{}'.", host);
+            return ProgramStatus::Failure;
         }
         Err(options::BuildError::MissingHostName) => {
             verbose!("Missing host name.");
@@ -402,36 +657,68 @@ fn run(matches: getopts::Matches) -> ProgramStatus {
         return ProgramStatus::Success;
     }
 
-    run_with_options(options)
-}
-
-fn run_with_options(options: options::Options) -> ProgramStatus {
-    let mut client = rancher::Client::new();
-
-    let api_key_path = config::api_key_path(&options.host_with_port());
-    debug!(
-        "Reading Rancher API key from {}",
-        api_key_path.to_string_lossy()
-    );
-    match std::fs::File::open(&api_key_path).map(std::io::BufReader::new) {
-        Ok(mut reader) => {
-            let mut string = String::new();
-            reader.read_to_string(&mut string).expect(
-                "failed to read api key",
-            );
-            client.api_key = serde_json::from_str(&string).expect("failed to parse json");
-            if let Some(ref key) = client.api_key {
-                debug!("Using Rancher API key {}", key.public_value);
-            }
+    match command {
+        Command::Connect => run_with_options(options),
+        _ if !options.protocol.has_environments() => {
+            fatal!("Only Rancher servers support this command.");
+            ProgramStatus::Failure
         }
-        Err(_) => {
-            debug!(
-                "{} No such file or directory",
-                api_key_path.to_string_lossy()
-            )
+        Command::Complete => {
+            let (prefix, partial) = completing.expect("completing without a path");
+            run_complete(options, listing, &prefix, &partial)
+        }
+        Command::Completion => unreachable!("completion scripts are printed by main"),
+        Command::Find => {
+            let pattern = match matches.free.get(1).map(|v| v.parse()) {
+                Some(Ok(v)) => v,
+                Some(Err(_)) => {
+                    fatal!("Bad pattern.");
+                    return ProgramStatus::Failure;
+                }
+                None => return ProgramStatus::FailureWithHelp,
+            };
+            run_find(options, pattern, matches.opt_present("connect"))
+        }
+        Command::Ls => run_ls(options, listing, matches.opt_present("json")),
+        Command::Keys => {
+            let prune = match matches.opt_str("prune").map(|v| v.parse::<u64>()) {
+                Some(Ok(v)) => Some(std::time::Duration::from_secs(v * 24 * 60 * 60)),
+                Some(Err(_)) => {
+                    fatal!("Bad number of days.");
+                    return ProgramStatus::Failure;
+                }
+                None => None,
+            };
+            run_keys(options, prune)
         }
+        Command::Login => run_login(options),
+        Command::Logout => run_logout(options),
+    }
+}
+
+/// The server named by `host` as `[user@][name]:path`, the default server
+/// when the name is left out, along with the user and the rest of `host`.
+fn server_name(host: &str, config: &config::Config) -> Option<(Option<String>, String, String)> {
+    if host.contains("://") {
+        return None;
+    }
+    let (user, arg) = match host.split('/').next().unwrap_or("").find('@') {
+        Some(i) => (Some(host[..i].to_string()), &host[i + 1..]),
+        None => (None, host),
     };
+    let first = arg.split('/').next().unwrap_or("");
+    match first.find(':') {
+        // a port follows the colon in host:port
+        Some(i) if first[i + 1..].starts_with(|c: char| c.is_digit(10)) => None,
+        // a bare host can't be told from an environment, so the default
+        // server is only used when asked for
+        Some(0) => Some((user, config.default_server().unwrap_or_default(), arg[1..].to_string())),
+        Some(i) => Some((user, arg[..i].to_string(), arg[i + 1..].to_string())),
+        None => None,
+    }
+}
 
+fn run_with_options(options: options::Options) -> ProgramStatus {
     let is_tty = match options.request_tty {
         options::RequestTTY::Force => true,
         options::RequestTTY::Auto => options.remote_command.starts_with("login -p -f "),
@@ -439,74 +726,92 @@ fn run_with_options(options: options::Options) -> ProgramStatus {
         options::RequestTTY::No => false,
     };
 
-    let mut tries = 0;
+    match options.protocol {
+        options::Protocol::Docker => run_docker(options, is_tty),
+        options::Protocol::Http | options::Protocol::Https => run_rancher(options, is_tty),
+        options::Protocol::Kubernetes => run_kubernetes(options, is_tty),
+    }
+}
+
+fn run_rancher(options: options::Options, is_tty: bool) -> ProgramStatus {
     let url = options.url();
-    let containers = loop {
-        match client.executeable_containers(
-            &url,
-            &options.environment,
-            &options.stack,
-            &options.service,
-        ) {
-            Ok(v) => break v,
-            Err(rancher::Error::Unauthorized) if tries == 0 => {
-                debug2!("Received Unauthorized, attempting authentication");
-                let user = prompt_with_default("Rancher User", users::get_current_username())
-                    .expect("couldn't get user");
-                let password = rpassword::prompt_password_stdout(&"Rancher Password: ")
-                    .expect("couldn't get password");
-                match client.ldap_auth(&url, &user, &password) {
-                    Ok(_) => (),
-                    Err(_) => {
-                        fatal!("Authentication failed.");
-                        return ProgramStatus::Failure;
-                    }
-                };
-                debug!("Writing {}", api_key_path.to_string_lossy());
-                let json_string =
-                    serde_json::to_string(&client.api_key).expect("failed to construct json");
-                let mut writer = std::fs::File::create(&api_key_path)
-                    .map(std::io::BufWriter::new)
-                    .expect("failed to write api key");
-                writer.write_all(json_string.as_bytes()).expect(
-                    "failed to write api key",
-                );
+    let exec = remote_exec(&options, is_tty);
 
+    let shared = match options.control_path {
+        // a menu of containers can only be shown here
+        Some(ref path) if !prompts_for_container(&options, is_tty) => {
+            control_execute(&options, std::path::Path::new(path), &exec, is_tty)
+        }
+        _ => None,
+    };
+    let host_access = match shared {
+        Some(v) => v,
+        None => {
+            let client = match rancher_client(&options) {
+                Ok(v) => v,
+                Err(status) => return status,
+            };
+            match rancher_execute(&client, &options, is_tty, exec) {
+                Ok(v) => v,
+                Err(status) => return status,
             }
+        }
+    };
+    debug2!("Got websocket address {}", host_access.url);
+
+    let tls = match load_tls(&options) {
+        Ok(v) => v,
+        Err(status) => return status,
+    };
+    let websocket_url = match host_access.authed_url(
+        options.websocket_host.as_ref().map(String::as_str),
+        options.websocket_protocol,
+    ) {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("{}.", e);
+            return ProgramStatus::Failure;
+        }
+    };
+    // connected before the terminal is in raw mode, as whether to trust the
+    // server's certificate may be asked
+    let host = websocket_url.host_str().unwrap_or("").to_string();
+    let port = websocket_url.port_or_known_default().unwrap_or(443);
+    let connection = upstream(&options).connect(&host, port);
+    let stream = match connection {
+        Ok(v) if websocket_url.scheme() == "wss" => match tls.connect(&host, port, v) {
+            Ok(v) => proxy::bridge(v),
+            // the host check has said why
+            Err(tls::Error::HostNotTrusted(_)) => return ProgramStatus::Failure,
             Err(e) => {
-                fatal!("{}", e);
+                fatal!("{}.", e);
                 return ProgramStatus::Failure;
             }
-        };
-        // need to give rancher a tiny bit of time to activate the api keys
-        std::thread::sleep(std::time::Duration::from_millis(250));
-        tries += 1;
+        },
+        v => v,
     };
-
-    if containers.len() == 0 {
-        fatal!("Couldn't find container.");
-        return ProgramStatus::Failure;
-    }
-
-    let container = match options.container {
-        options::Container::First => &containers[0],
-        options::Container::Auto if containers.len() == 1 || !is_tty => &containers[0],
-        options::Container::Menu | options::Container::Auto => prompt::user_choice(&containers).expect("failed to get container choice"),
+    let stream = match stream {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("Couldn't connect to {}:{}: {}.", host, port, e);
+            return ProgramStatus::Failure;
+        }
     };
+    let escape_char = options.escape_char;
+    let status = with_raw_mode(is_tty, || {
+        connect(websocket_url, stream, get_input(escape_char))
+    });
+    info!("\nConnection to {} closed.", url);
+    status
+}
 
-    let execute_url = container.actions.get("execute").expect(
-        "expected executeable container",
-    );
-
+/// The command run in the container, exporting the `SendEnv` variables and
+/// giving it a tty when `is_tty`.
+fn remote_exec(options: &options::Options, is_tty: bool) -> Vec<String> {
     let mut command_parts = Vec::new();
-    let mut send_env_patterns = options.send_env;
-    send_env_patterns.push("TERM".parse().unwrap());
-    let send_env = pattern::PatternList::from(send_env_patterns);
-    for (key, val) in std::env::vars() {
-        if send_env.matches(&key) {
-            command_parts.push(format!("{}={}", key, val));
-            command_parts.push(format!("export {}", key));
-        }
+    for (key, val) in send_env_vars(options.send_env.clone()) {
+        command_parts.push(format!("{}={}", key, val));
+        command_parts.push(format!("export {}", key));
     }
 
     if is_tty {
@@ -520,83 +825,953 @@ fn run_with_options(options: options::Options) -> ProgramStatus {
             options.remote_command
         ));
     } else {
-        command_parts.push(options.remote_command);
+        command_parts.push(options.remote_command.clone());
     }
 
-    let exec = vec![
+    vec![
         String::from("/bin/sh"),
         String::from("-c"),
         command_parts.join("; "),
-    ];
+    ]
+}
+
+/// Finds the container for `options` and starts `exec` in it, giving the
+/// websocket to connect to.
+fn rancher_execute(
+    client: &rancher::Client,
+    options: &options::Options,
+    is_tty: bool,
+    exec: Vec<String>,
+) -> Result<HostAccess, ProgramStatus> {
+    let url = options.url();
+
+    let environment = match options.environment {
+        Some(ref v) => v.to_owned(),
+        None => default_environment(client, options, &url, is_tty)?,
+    };
+    debug!("Using environment {}", environment);
+
+    let stack = match options.stack {
+        Some(ref v) => v.to_owned(),
+        None => {
+            let stacks = client
+                .stacks_with_service(&url, &environment, &options.service)
+                .map_err(|e| rancher_error(options, e))?;
+            match stacks.len() {
+                0 => {
+                    fatal!("Couldn't find service {} in any stack.", options.service);
+                    return Err(ProgramStatus::Failure);
+                }
+                1 => stacks[0].to_owned(),
+                _ if is_tty && !options.batch_mode => {
+                    prompt::user_choice("Select a stack", &stacks)
+                        .expect("failed to get stack choice")
+                        .to_owned()
+                }
+                _ => {
+                    fatal!(
+                        "Service {} is in more than one stack: {}.",
+                        options.service,
+                        stacks.join(", ")
+                    );
+                    return Err(ProgramStatus::Failure);
+                }
+            }
+        }
+    };
+    debug!("Using stack {}", stack);
+
+    let containers = client
+        .executeable_containers(&url, &environment, &stack, &options.service)
+        .map_err(|e| rancher_error(options, e))?;
+
+    if containers.len() == 0 {
+        fatal!("Couldn't find container.");
+        return Err(ProgramStatus::Failure);
+    }
+
+    let container = choose_container(options, is_tty, &containers);
+
+    let execute_url = container.actions.get("execute").expect(
+        "expected executeable container",
+    );
+
     debug!("Making execute request");
     debug3!("Using command {:?} and is_tty: {}", exec, is_tty);
-    let host_access: HostAccess = client
+    client
         .post(execute_url, &ContainerExec::new(exec, is_tty))
-        .expect("execute failed");
-    debug2!("Got websocket address {}", host_access.url);
+        .map_err(|e| rancher_error(options, e))
+}
 
-    let status;
-    {
-        // raw mode will stay in effect until the raw var is dropped
-        // we otherwise don't actually need it for anything
-        let raw = if is_tty {
-            debug3!("Entering raw mode");
-            let mut stdout = std::io::stdout().into_raw_mode().unwrap();
-            stdout.flush().unwrap();
-            Some(stdout)
-        } else {
-            debug3!("Not a TTY skipping raw mode");
+/// Has the control master at `path` start `exec`, first starting the master
+/// when `ControlMaster` asks for it. `None` when there's no master to use, or
+/// it couldn't find the container.
+fn control_execute(
+    options: &options::Options,
+    path: &std::path::Path,
+    exec: &[String],
+    is_tty: bool,
+) -> Option<HostAccess> {
+    let start = match options.control_master {
+        options::ControlMaster::Auto => !control::is_running(path),
+        options::ControlMaster::No => false,
+        options::ControlMaster::Yes => true,
+    };
+    if start && !start_control_master(options, path) {
+        return None;
+    }
+
+    let request = control::Request {
+        environment: options.environment.clone(),
+        stack: options.stack.clone(),
+        service: options.service.clone(),
+        command: exec.to_vec(),
+        tty: is_tty,
+    };
+    match control::request(path, &request) {
+        Ok(Ok(v)) => {
+            debug!("Using control master {}", path.to_string_lossy());
+            Some(v)
+        }
+        Ok(Err(e)) => {
+            debug!("Control master couldn't start command: {}", e);
             None
+        }
+        Err(e) => {
+            debug!("No control master at {}: {}", path.to_string_lossy(), e);
+            None
+        }
+    }
+}
+
+fn start_control_master(options: &options::Options, path: &std::path::Path) -> bool {
+    let listener = match control::listen(path) {
+        Ok(v) => v,
+        // ControlMaster yes with a master already running, which is left alone
+        Err(ref e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            error!(
+                "ControlSocket {} already exists, disabling multiplexing",
+                path.to_string_lossy()
+            );
+            return false;
+        }
+        Err(e) => {
+            error!("Couldn't listen on control socket {}: {}", path.to_string_lossy(), e);
+            return false;
+        }
+    };
+
+    // the master is detached, batch mode stops it prompting or showing menus
+    let mut master_options = options.clone();
+    master_options.batch_mode = true;
+    let persist = std::time::Duration::from_secs(options.control_persist);
+    verbose!("Starting control master {}", path.to_string_lossy());
+    let result = control::spawn(move || {
+        let url = master_options.url();
+        let key_store = key_store(&master_options);
+        let mut client = match rancher_client(&master_options) {
+            Ok(v) => v,
+            Err(_) => return,
         };
-        status = connect(host_access.authed_url(), get_input(options.escape_char));
-        // don't really need to do this, but the compiler wants us to use raw
-        // for *something*
-        match raw {
-            Some(mut stdout) => stdout.flush().unwrap(),
-            None => (),
-        };
+        // pick up the API key stored by a client that had to log in again
+        client.on_unauthorized(move |_| match credentials::load_stored(&url, &key_store) {
+            Ok(Some(v)) => Ok(v),
+            Ok(None) => Err(rancher::Error::Unauthorized),
+            Err(e) => {
+                error!("Couldn't read {}: {}.", key_store, e);
+                Err(rancher::Error::Unauthorized)
+            }
+        });
+        control::serve(listener, path, persist, |request| {
+            let mut options = master_options.clone();
+            options.environment = request.environment;
+            options.stack = request.stack;
+            options.service = request.service;
+            rancher_execute(&client, &options, request.tty, request.command).map_err(|_| {
+                format!("couldn't start command in {}", options.service)
+            })
+        });
+    });
+    match result {
+        Ok(_) => true,
+        Err(e) => {
+            error!("Couldn't start control master: {}", e);
+            false
+        }
     }
-    info!("\nConnection to {} closed.", url);
-    status
 }
 
-fn get_input(escape_char: Option<char>) -> futures::sync::mpsc::Receiver<websocket::OwnedMessage> {
-    let (sender, receiver) = futures::sync::mpsc::channel(0);
-    std::thread::spawn(move || {
-        let mut escape_scanner = escape::scanner(escape_char);
-        let mut stdin = std::io::stdin();
-        let mut buffer = [0; 4096];
-        let mut vbuffer = std::vec::Vec::new();
-        let mut sink = sender.wait();
-        'main: loop {
-            escape_scanner.reset();
-            let mut sent = 0;
-            let read = stdin.read(&mut buffer[..]).unwrap();
-            while sent < read {
-                let escape_type = escape_scanner.next_escape(&buffer, read);
-                let bytes = match escape_type {
-                    escape::Escape::DecreaseVerbosity |
-                    escape::Escape::Help |
-                    escape::Escape::IncreaseVerbosity |
-                    escape::Escape::Itself |
-                    escape::Escape::Suspend |
-                    escape::Escape::Terminate => &buffer[sent..escape_scanner.pos() - 1],
-                    escape::Escape::Invalid => {
-                        vbuffer.clear();
-                        vbuffer.push(escape_scanner.char() as u8);
-                        vbuffer.extend(&buffer[sent..(escape_scanner.pos())]);
-                        &vbuffer[..]
-                    }
-                    escape::Escape::Literal | escape::Escape::None => {
-                        &buffer[sent..(escape_scanner.pos())]
-                    }
-                };
-                let message = base64::encode(bytes);
-                sink.send(websocket::OwnedMessage::Text(message)).unwrap();
-                sent = escape_scanner.pos();
-                match escape_type {
-                    escape::Escape::DecreaseVerbosity => {
-                        let level = log::decrease_level();
+fn prompts_for_container(options: &options::Options, is_tty: bool) -> bool {
+    match options.container {
+        _ if options.batch_mode => false,
+        options::Container::First => false,
+        options::Container::Auto => is_tty,
+        options::Container::Menu => true,
+    }
+}
+
+/// The environment to use when none was given, the account's default, the
+/// only one it can access, or the user's choice from a menu.
+fn default_environment(
+    client: &rancher::Client,
+    options: &options::Options,
+    url: &url::Url,
+    is_tty: bool,
+) -> Result<String, ProgramStatus> {
+    match client.default_environment(url) {
+        Ok(Some(v)) => return Ok(v),
+        Ok(None) => (),
+        Err(e) => return Err(rancher_error(options, e)),
+    };
+    let environments = match client.environments(url) {
+        Ok(v) => v,
+        Err(e) => return Err(rancher_error(options, e)),
+    };
+    match environments.len() {
+        0 => {
+            fatal!("Couldn't find any environments.");
+            Err(ProgramStatus::Failure)
+        }
+        1 => Ok(environments[0].to_owned()),
+        _ if is_tty && !options.batch_mode => {
+            Ok(
+                prompt::user_choice("Select an environment", &environments)
+                    .expect("failed to get environment choice")
+                    .to_owned(),
+            )
+        }
+        _ => {
+            fatal!(
+                "More than one environment, specify one of: {}.",
+                environments.join(", ")
+            );
+            Err(ProgramStatus::Failure)
+        }
+    }
+}
+
+fn run_find(
+    mut options: options::Options,
+    pattern: pattern::Pattern,
+    connect: bool,
+) -> ProgramStatus {
+    let url = options.url();
+    let client = match rancher_client(&options) {
+        Ok(v) => v,
+        Err(status) => return status,
+    };
+
+    // a pattern with a slash is matched against the whole path
+    let full_path = pattern.to_string().contains('/');
+    let found = client.find_services(&url, |s| if full_path {
+        pattern.matches(&s.to_string())
+    } else {
+        pattern.matches(&s.service)
+    });
+    let mut services = match found {
+        Ok(v) => v,
+        Err(e) => return rancher_error(&options, e),
+    };
+
+    if services.len() == 0 {
+        fatal!("No services match {}.", pattern);
+        return ProgramStatus::Failure;
+    }
+    if !connect || services.len() > 1 {
+        for service in services {
+            println!("{}", service);
+        }
+        return ProgramStatus::Success;
+    }
+
+    let service = services.remove(0);
+    verbose!("Connecting to {}", service);
+    options.environment = Some(service.environment);
+    options.stack = Some(service.stack);
+    options.service = service.service;
+    run_with_options(options)
+}
+
+fn run_login(options: options::Options) -> ProgramStatus {
+    let url = options.url();
+    let client = match rancher_client(&options) {
+        Ok(v) => v,
+        Err(status) => return status,
+    };
+
+    // checks the API key, logging in again if it's been revoked
+    let result = match client.api_key() {
+        Some(_) => client.api_keys(&url).map(|_| ()),
+        None => client.authenticate(),
+    };
+    if let Err(e) = result {
+        return rancher_error(&options, e);
+    }
+    match client.api_key() {
+        Some(key) => info!("Logged in to {} with API key {}.", url, key.public_value),
+        None => info!("Logged in to {}.", url),
+    };
+    ProgramStatus::Success
+}
+
+fn run_logout(options: options::Options) -> ProgramStatus {
+    let url = options.url();
+    let key_store = key_store(&options);
+
+    // only keys rsh stored are removed, not those from RANCHER_ACCESS_KEY or
+    // the Rancher CLI
+    let api_key = match credentials::load_stored(&url, &key_store) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            info!("Not logged in to {}.", url);
+            return ProgramStatus::Success;
+        }
+        Err(e) => {
+            fatal!("Couldn't read {}: {}.", key_store, e);
+            return if options.batch_mode {
+                ProgramStatus::Unauthorized
+            } else {
+                ProgramStatus::Failure
+            };
+        }
+    };
+    let public_value = api_key.public_value.clone();
+    let client = match connected_client(&options) {
+        Ok(v) => v,
+        Err(status) => return status,
+    };
+    client.set_api_key(Some(api_key));
+
+    match client
+        .api_key_info(&url, &public_value)
+        .and_then(|k| client.revoke_api_key(&k)) {
+        Ok(_) => (),
+        Err(rancher::Error::NotFound) => {
+            verbose!("API key {} was already revoked.", public_value)
+        }
+        Err(rancher::Error::Unauthorized) => {
+            fatal!(
+                "The Rancher server rejected API key {}, remove {} if it was revoked.",
+                public_value,
+                key_store
+            );
+            return ProgramStatus::Failure;
+        }
+        Err(e) => {
+            fatal!("Couldn't revoke API key {}: {}.", public_value, e);
+            return ProgramStatus::Failure;
+        }
+    };
+
+    if let Some(ref key) = client.api_key() {
+        if let Err(e) = credentials::remove(&url, &key_store, key) {
+            fatal!("Couldn't remove {}: {}.", key_store, e);
+            return ProgramStatus::Failure;
+        }
+    }
+    info!("Logged out of {}.", url);
+    ProgramStatus::Success
+}
+
+/// Lists the API keys rsh created, or with `prune` revokes those not in use
+/// that are inactive or older than it.
+fn run_keys(options: options::Options, prune: Option<std::time::Duration>) -> ProgramStatus {
+    let url = options.url();
+    let client = match rancher_client(&options) {
+        Ok(v) => v,
+        Err(status) => return status,
+    };
+
+    let api_keys = match client.api_keys(&url) {
+        Ok(v) => v,
+        Err(e) => return rancher_error(&options, e),
+    };
+    let current = client.api_key().map(|k| k.public_value);
+
+    let mut status = ProgramStatus::Success;
+    for api_key in api_keys {
+        let in_use = current.as_ref() == Some(&api_key.public_value);
+        let stale = prune.map_or(false, |v| api_key.is_stale(v));
+        if stale && !in_use {
+            match client.revoke_api_key(&api_key) {
+                Ok(_) => println!("{} revoked", api_key),
+                Err(e) => {
+                    error!("Couldn't revoke API key {}: {}.", api_key, e);
+                    status = ProgramStatus::Failure;
+                }
+            };
+        } else {
+            println!(
+                "{}\t{}\t{}{}",
+                api_key,
+                api_key.state,
+                api_key.created.as_ref().map(String::as_ref).unwrap_or("-"),
+                if in_use { "\t(in use)" } else { "" }
+            );
+        }
+    }
+    status
+}
+
+fn complete_host(word: &str, config: &config::Config) {
+    // keep any protocol or user, completing only the host
+    let start = word.rfind('@')
+        .map(|i| i + 1)
+        .or_else(|| word.find("://").map(|i| i + 3))
+        .unwrap_or(0);
+    let (prefix, partial) = word.split_at(start);
+
+    if !prefix.contains("://") {
+        for name in config.server_names() {
+            if name.starts_with(partial) {
+                println!("{}{}:", prefix, name);
+            }
+        }
+    }
+    for host in config.hosts() {
+        if host.starts_with(partial) {
+            println!("{}{}/", prefix, host);
+        }
+    }
+}
+
+fn run_complete(
+    options: options::Options,
+    path: Vec<String>,
+    prefix: &str,
+    partial: &str,
+) -> ProgramStatus {
+    // nothing follows the service
+    if path.len() > 2 {
+        return ProgramStatus::Success;
+    }
+
+    let url = options.url();
+    let cache = cache::Cache::new(
+        config::cache_dir(),
+        std::time::Duration::from_secs(std::cmp::min(options.cache_ttl, COMPLETION_CACHE_TTL)),
+    );
+    let key = format!("complete {}/{}", url.as_str().trim_right_matches('/'), path.join("/"));
+
+    let names: Vec<String> = match cache.get(&key) {
+        Some(v) => v,
+        None => {
+            let client = match rancher_client(&options) {
+                Ok(v) => v,
+                Err(status) => return status,
+            };
+            // listing environments counts their stacks, which isn't needed
+            let result = if path.is_empty() {
+                client.environments(&url)
+            } else {
+                client.list(&url, &path).map(|listing| {
+                    listing.into_iter().map(|l| l.name).collect()
+                })
+            };
+            let names = match result {
+                Ok(v) => v,
+                Err(e) => return rancher_error(&options, e),
+            };
+            if let Err(e) = cache.set(&key, &names) {
+                debug!("Couldn't cache completions: {}", e);
+            }
+            names
+        }
+    };
+
+    // environments and stacks are followed by more of the path
+    let suffix = if path.len() < 2 { "/" } else { "" };
+    for name in names.iter().filter(|n| n.starts_with(partial)) {
+        println!("{}{}{}", prefix, name, suffix);
+    }
+    ProgramStatus::Success
+}
+
+fn run_ls(options: options::Options, path: Vec<String>, json: bool) -> ProgramStatus {
+    let url = options.url();
+    let client = match rancher_client(&options) {
+        Ok(v) => v,
+        Err(status) => return status,
+    };
+
+    let listing = match client.list(&url, &path) {
+        Ok(v) => v,
+        Err(rancher::Error::Empty) => {
+            fatal!("No such environment, stack, or service {}.", path.join("/"));
+            return ProgramStatus::Failure;
+        }
+        Err(e) => return rancher_error(&options, e),
+    };
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&listing).expect("failed to construct json")
+        );
+    } else {
+        for entry in listing {
+            match entry.count {
+                Some(count) => println!("{}\t{}\t{}", entry.name, entry.state, count),
+                // containers have nothing below them to count
+                None if path.len() < 3 => println!("{}\t{}\t?", entry.name, entry.state),
+                None => println!("{}\t{}", entry.name, entry.state),
+            };
+        }
+    }
+    ProgramStatus::Success
+}
+
+fn run_docker(options: options::Options, is_tty: bool) -> ProgramStatus {
+    let endpoint = match docker::Endpoint::new(&options.host_name, options.port) {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("{}", e);
+            return ProgramStatus::Failure;
+        }
+    };
+    let client = docker::Client::new(endpoint);
+
+    let project = match options.stack {
+        Some(ref v) => v,
+        None => {
+            verbose!("Missing stack.");
+            return ProgramStatus::FailureWithHelp;
+        }
+    };
+    let containers = match client.executeable_containers(project, &options.service) {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("{}", e);
+            return ProgramStatus::Failure;
+        }
+    };
+
+    if containers.len() == 0 {
+        fatal!("Couldn't find container.");
+        return ProgramStatus::Failure;
+    }
+
+    let container = choose_container(&options, is_tty, &containers);
+
+    let env = send_env_vars(options.send_env)
+        .into_iter()
+        .map(|(key, val)| format!("{}={}", key, val))
+        .collect();
+    let exec = vec![
+        String::from("/bin/sh"),
+        String::from("-c"),
+        options.remote_command,
+    ];
+    debug!("Making exec request");
+    debug3!("Using command {:?} and is_tty: {}", exec, is_tty);
+    let exec_id = match client.create_exec(container, &docker::ContainerExec::new(exec, env, is_tty)) {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("{}", e);
+            return ProgramStatus::Failure;
+        }
+    };
+    let stream = match client.start_exec(&exec_id, is_tty) {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("{}", e);
+            return ProgramStatus::Failure;
+        }
+    };
+
+    if is_tty {
+        if let Ok((cols, rows)) = termion::terminal_size() {
+            if let Err(e) = client.resize_exec(&exec_id, cols, rows) {
+                debug!("Couldn't resize terminal: {}", e);
+            }
+        }
+    }
+
+    let escape_char = options.escape_char;
+    let status = with_raw_mode(is_tty, || {
+        connect_docker(stream, is_tty, get_input(escape_char))
+    });
+    info!("\nConnection to {} closed.", container);
+    status
+}
+
+fn run_kubernetes(options: options::Options, is_tty: bool) -> ProgramStatus {
+    let context = options.context.as_ref().unwrap_or(&options.host_name);
+    let client = match kubernetes::Client::from_kubeconfig(context) {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("{}", e);
+            return ProgramStatus::Failure;
+        }
+    };
+
+    let namespace = match options.stack {
+        Some(ref v) => v,
+        None => {
+            verbose!("Missing stack.");
+            return ProgramStatus::FailureWithHelp;
+        }
+    };
+    let pods = match client.executeable_pods(namespace, &options.service) {
+        Ok(v) => v,
+        Err(kubernetes::Error::Unauthorized) => {
+            fatal!("Permission denied by the Kubernetes API server.");
+            return ProgramStatus::Unauthorized;
+        }
+        Err(e) => {
+            fatal!("{}", e);
+            return ProgramStatus::Failure;
+        }
+    };
+
+    if pods.len() == 0 {
+        fatal!("Couldn't find container.");
+        return ProgramStatus::Failure;
+    }
+
+    let pod = choose_container(&options, is_tty, &pods);
+
+    let mut command_parts = Vec::new();
+    for (key, val) in send_env_vars(options.send_env) {
+        command_parts.push(format!("{}={}", key, val));
+        command_parts.push(format!("export {}", key));
+    }
+    command_parts.push(options.remote_command);
+
+    let exec = vec![
+        String::from("/bin/sh"),
+        String::from("-c"),
+        command_parts.join("; "),
+    ];
+    debug3!("Using command {:?} and is_tty: {}", exec, is_tty);
+    let exec_url = client.exec_url(namespace, pod, &exec, is_tty);
+    debug2!("Using websocket address {}", exec_url);
+
+    let terminal_size = if is_tty {
+        termion::terminal_size().ok().map(|(cols, rows)| {
+            kubernetes::TerminalSize::new(cols, rows)
+        })
+    } else {
+        None
+    };
+
+    let escape_char = options.escape_char;
+    let status = with_raw_mode(is_tty, || {
+        connect_kubernetes(&client, exec_url, terminal_size, get_input(escape_char))
+    });
+    info!("\nConnection to {} closed.", pod);
+    status
+}
+
+/// Prompts for a user and password to log in to the Rancher server with,
+/// giving up after `NumberOfPasswordPrompts` rejected attempts.
+fn prompt_for_api_key(
+    client: &rancher::Client,
+    url: &url::Url,
+    provider: options::AuthProvider,
+    number_of_password_prompts: u16,
+) -> Result<rancher::ApiKey, rancher::Error> {
+    let mut prompts = 0;
+    let mut default_user = users::get_current_username();
+    loop {
+        if number_of_password_prompts == 0 {
+            return Err(rancher::Error::Unauthorized);
+        }
+        prompts += 1;
+        let user = prompt_with_default("Rancher User", default_user.take())
+            .map_err(rancher::Error::PromptError)?;
+        let password = prompt_password("Rancher Password").map_err(rancher::Error::PromptError)?;
+        match client.auth(url, provider, &user, &password) {
+            Err(rancher::Error::BadCredentials) if prompts < number_of_password_prompts => {
+                error!("Permission denied, please try again.");
+                default_user = Some(user);
+            }
+            v => return v,
+        };
+    }
+}
+
+fn rancher_error(options: &options::Options, err: rancher::Error) -> ProgramStatus {
+    match err {
+        rancher::Error::Unauthorized if options.batch_mode => {
+            fatal!("Permission denied, no valid Rancher API key and BatchMode is set.");
+            return ProgramStatus::Unauthorized;
+        }
+        rancher::Error::Unauthorized => fatal!("Permission denied."),
+        rancher::Error::BadCredentials => {
+            fatal!("Authentication failed, incorrect user or password.")
+        }
+        rancher::Error::AuthProviderMismatch(configured, server) => {
+            fatal!(
+                "Authentication failed, the Rancher server uses auth provider {} not {}, see AuthProvider in rsh_config(5).",
+                server,
+                configured
+            )
+        }
+        rancher::Error::UnsupportedAuthProvider(name) => {
+            fatal!(
+                "Authentication failed, auth provider {} is not supported.",
+                name
+            )
+        }
+        rancher::Error::PromptError(e) => {
+            fatal!("Permission denied, couldn't ask for a user and password: {}.", e)
+        }
+        // the host check has said why
+        rancher::Error::HttpError(http::Error::TlsError(tls::Error::HostNotTrusted(_))) => (),
+        e => fatal!("{}", e),
+    };
+    ProgramStatus::Failure
+}
+
+fn key_store(options: &options::Options) -> credentials::KeyStore {
+    match options.credential_helper {
+        Some(ref command) => credentials::KeyStore::Helper(command.to_owned()),
+        None => credentials::KeyStore::File(credentials::KeyFile {
+            path: config::api_key_path(&options.api_key_name()),
+            encrypt: options.encrypt_keys,
+            strict_modes: options.strict_modes,
+            batch_mode: options.batch_mode,
+        }),
+    }
+}
+
+fn rancher_client(options: &options::Options) -> Result<rancher::Client, ProgramStatus> {
+    let url = options.url();
+    let key_store = key_store(options);
+    let mut client = connected_client(options)?;
+    client.set_api_keys(credentials::load(&url, key_store.clone()));
+    if let Some(ref key) = client.api_key() {
+        debug!("Using Rancher API key {}", key.public_value);
+    }
+    if options.cache_ttl > 0 {
+        client.use_cache(cache::Cache::new(
+            config::cache_dir(),
+            std::time::Duration::from_secs(options.cache_ttl),
+        ));
+    }
+    if !options.batch_mode {
+        let provider = options.auth_provider;
+        let number_of_password_prompts = options.number_of_password_prompts;
+        client.on_unauthorized(move |c| {
+            let api_key = prompt_for_api_key(c, &url, provider, number_of_password_prompts)?;
+            // the key is on the server by now, so is used all the same
+            if let Err(e) = credentials::store(&url, &key_store, &api_key) {
+                error!("Couldn't write {}: {}.", key_store, e);
+                error!("Rancher API key {} is only used this time, see rsh keys to revoke it.", api_key.public_value);
+            }
+            Ok(api_key)
+        });
+    }
+    Ok(client)
+}
+
+/// A client without an API key, connecting through any proxy command or
+/// proxy, and with the certificates from the options.
+fn connected_client(options: &options::Options) -> Result<rancher::Client, ProgramStatus> {
+    let tls = load_tls(options)?;
+    Ok(rancher::Client::new(http::Client::new(upstream(options), tls)))
+}
+
+fn load_tls(options: &options::Options) -> Result<tls::Tls, ProgramStatus> {
+    let mut tls = tls::load(options).map_err(|e| {
+        fatal!("{}.", e);
+        ProgramStatus::Failure
+    })?;
+    let strict_host_key_checking = options.strict_host_key_checking;
+    let batch_mode = options.batch_mode;
+    // each certificate is only asked about once, whether or not it could be
+    // recorded, and one at a time
+    let trusted = std::sync::Mutex::new(std::collections::HashSet::new());
+    tls.check_host = Some(std::sync::Arc::new(move |name: &str, fingerprint: &str| {
+        let mut trusted = trusted.lock().expect("poisoned known hosts");
+        let certificate = (name.to_string(), fingerprint.to_string());
+        if trusted.contains(&certificate) {
+            return true;
+        }
+        if !check_known_host(strict_host_key_checking, batch_mode, name, fingerprint) {
+            return false;
+        }
+        trusted.insert(certificate);
+        true
+    }));
+    Ok(tls)
+}
+
+/// Compares the certificate of the server `name`, a `host:port`, to the one
+/// recorded in the known hosts file, recording it when it's new and refusing
+/// it when it's changed, as `StrictHostKeyChecking` says.
+fn check_known_host(
+    strict_host_key_checking: options::StrictHostKeyChecking,
+    batch_mode: bool,
+    name: &str,
+    fingerprint: &str,
+) -> bool {
+    let path = config::known_hosts_path();
+    debug!("Server certificate key fingerprint is {}", fingerprint);
+
+    let status = match known_hosts::check(&path, name, fingerprint) {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("Couldn't read {}: {}.", path.to_string_lossy(), e);
+            return false;
+        }
+    };
+    match status {
+        known_hosts::Status::Known => true,
+        known_hosts::Status::Changed(line) => {
+            error!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            error!("@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @");
+            error!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            error!("IT IS POSSIBLE THAT SOMEONE IS DOING SOMETHING NASTY!");
+            error!("Someone could be eavesdropping on you right now (man-in-the-middle attack)!");
+            error!("It is also possible that the server's certificate has just been replaced with one for a new key.");
+            error!("The fingerprint of the key in the certificate sent by {} is", name);
+            error!("{}.", fingerprint);
+            error!("Please contact your Rancher administrator.");
+            error!("Offending key in {}:{}", path.to_string_lossy(), line);
+            error!("Remove that line from the file to accept the new key.");
+            if strict_host_key_checking == options::StrictHostKeyChecking::No {
+                return true;
+            }
+            fatal!("Certificate key for {} has changed and you have requested strict checking.", name);
+            false
+        }
+        known_hosts::Status::Unknown => {
+            match strict_host_key_checking {
+                options::StrictHostKeyChecking::Yes => {
+                    fatal!("No key is known for {} and you have requested strict checking.", name);
+                    return false;
+                }
+                options::StrictHostKeyChecking::Ask => {
+                    if batch_mode {
+                        fatal!("No key is known for {} and BatchMode is set.", name);
+                        return false;
+                    }
+                    info!("The authenticity of host '{}' can't be established.", name);
+                    info!("Its certificate key fingerprint is {}.", fingerprint);
+                    // no terminal to answer on is taken as no
+                    let answer = prompt_with_default("Are you sure you want to continue connecting (yes/no)", None)
+                        .unwrap_or_default();
+                    if answer != "yes" {
+                        fatal!("Host certificate verification failed.");
+                        return false;
+                    }
+                }
+                options::StrictHostKeyChecking::AcceptNew | options::StrictHostKeyChecking::No => (),
+            };
+            match known_hosts::add(&path, name, fingerprint) {
+                Ok(_) => info!("Permanently added '{}' ({}) to the list of known hosts.", name, fingerprint),
+                Err(e) => error!("Failed to add the host to the list of known hosts ({}): {}.", path.to_string_lossy(), e),
+            };
+            true
+        }
+    }
+}
+
+/// Where connections to servers are made, through any proxy command or
+/// proxy.
+fn upstream(options: &options::Options) -> proxy::Upstream {
+    let upstream = match (&options.proxy_command, &options.proxy_url) {
+        (&Some(ref command), _) => proxy::Upstream::Command(command.clone()),
+        (&None, &Some(ref proxy)) => proxy::Upstream::Proxy(proxy.clone()),
+        (&None, &None) => proxy::Upstream::Direct,
+    };
+    debug!("Using {}", upstream);
+    upstream
+}
+
+fn choose_container<'a, T: std::fmt::Display>(
+    options: &options::Options,
+    is_tty: bool,
+    containers: &'a [T],
+) -> &'a T {
+    match options.container {
+        _ if options.batch_mode => &containers[0],
+        options::Container::First => &containers[0],
+        options::Container::Auto if containers.len() == 1 || !is_tty => &containers[0],
+        options::Container::Menu | options::Container::Auto => prompt::user_choice("Select a container", containers)
+            .expect("failed to get container choice"),
+    }
+}
+
+fn send_env_vars(mut patterns: Vec<pattern::Pattern>) -> Vec<(String, String)> {
+    patterns.push("TERM".parse().unwrap());
+    let send_env = pattern::PatternList::from(patterns);
+    std::env::vars().filter(|&(ref key, _)| send_env.matches(key)).collect()
+}
+
+fn with_raw_mode<F: FnOnce() -> ProgramStatus>(is_tty: bool, session: F) -> ProgramStatus {
+    // raw mode will stay in effect until the raw var is dropped
+    // we otherwise don't actually need it for anything
+    let raw = if is_tty {
+        debug3!("Entering raw mode");
+        let mut stdout = std::io::stdout().into_raw_mode().unwrap();
+        stdout.flush().unwrap();
+        Some(stdout)
+    } else {
+        debug3!("Not a TTY skipping raw mode");
+        None
+    };
+    let status = session();
+    // don't really need to do this, but the compiler wants us to use raw
+    // for *something*
+    match raw {
+        Some(mut stdout) => stdout.flush().unwrap(),
+        None => (),
+    };
+    status
+}
+
+fn get_input(escape_char: Option<char>) -> futures::sync::mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = futures::sync::mpsc::channel(0);
+    std::thread::spawn(move || {
+        let mut escape_scanner = escape::scanner(escape_char);
+        let mut stdin = std::io::stdin();
+        let mut buffer = [0; 4096];
+        let mut vbuffer = std::vec::Vec::new();
+        let mut sink = sender.wait();
+        'main: loop {
+            escape_scanner.reset();
+            let mut sent = 0;
+            let read = match stdin.read(&mut buffer[..]) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Ok(0) | Err(_) => {
+                    // an empty message says stdin has ended, after which the
+                    // session goes on until the other end closes it
+                    let _ = sink.send(Vec::new());
+                    loop {
+                        std::thread::park();
+                    }
+                }
+                Ok(v) => v,
+            };
+            while sent < read {
+                let escape_type = escape_scanner.next_escape(&buffer, read);
+                let bytes = match escape_type {
+                    escape::Escape::DecreaseVerbosity |
+                    escape::Escape::Help |
+                    escape::Escape::IncreaseVerbosity |
+                    escape::Escape::Itself |
+                    escape::Escape::Suspend |
+                    escape::Escape::Terminate => &buffer[sent..escape_scanner.pos() - 1],
+                    escape::Escape::Invalid => {
+                        vbuffer.clear();
+                        vbuffer.push(escape_scanner.char() as u8);
+                        vbuffer.extend(&buffer[sent..(escape_scanner.pos())]);
+                        &vbuffer[..]
+                    }
+                    escape::Escape::Literal | escape::Escape::None => {
+                        &buffer[sent..(escape_scanner.pos())]
+                    }
+                };
+                if !bytes.is_empty() {
+                    sink.send(bytes.to_vec()).unwrap();
+                }
+                sent = escape_scanner.pos();
+                match escape_type {
+                    escape::Escape::DecreaseVerbosity => {
+                        let level = log::decrease_level();
                         println!("{}V [LogLevel {}]\r", escape_scanner.char(), level);
                     }
                     escape::Escape::Help => {
@@ -635,14 +1810,14 @@ Supported escape sequences:\r
 
 fn connect(
     websocket_url: url::Url,
-    stdin: futures::sync::mpsc::Receiver<websocket::OwnedMessage>,
+    stream: proxy::Connection,
+    stdin: futures::sync::mpsc::Receiver<Vec<u8>>,
 ) -> ProgramStatus {
     let mut core = tokio_core::reactor::Core::new().unwrap();
     let mut stdout = std::io::stdout();
 
     debug!("Connecting to websocket\r");
-    let runner = websocket::ClientBuilder::from_url(&websocket_url)
-        .async_connect(None, &core.handle())
+    let runner = connect_websocket(&websocket_url, stream, &core.handle())
         .and_then(|(duplex, _)| {
             let (sink, stream) = duplex.split();
             and_select::new(
@@ -658,7 +1833,142 @@ fn connect(
                     websocket::OwnedMessage::Ping(d) => Some(websocket::OwnedMessage::Pong(d)),
                     _ => None,
                 }),
-                stdin.map_err(|_| websocket::result::WebSocketError::NoDataAvailable),
+                stdin
+                    .filter(|bytes| !bytes.is_empty())
+                    .map(|bytes| websocket::OwnedMessage::Text(base64::encode(&bytes)))
+                    .map_err(|_| websocket::result::WebSocketError::NoDataAvailable),
+            ).forward(sink)
+        });
+
+    match core.run(runner) {
+        Ok(_) => debug3!("connection closed successfully"),
+        Err(e) => debug3!("connection closed with error {:?}", e),
+    };
+
+    ProgramStatus::Success
+}
+
+// websocket can't connect through a proxy or proxy command, nor check the
+// server's certificate as rsh does, so it's given the connection rsh made
+fn connect_websocket(
+    websocket_url: &url::Url,
+    stream: proxy::Connection,
+    handle: &tokio_core::reactor::Handle,
+) -> websocket::client::async::ClientNew<Box<websocket::stream::async::Stream + Send>> {
+    let builder = websocket::ClientBuilder::new(websocket_url.as_str()).expect("bad websocket URL");
+    let stream = match stream
+        .set_nonblocking(true)
+        .and_then(|_| tokio_core::reactor::PollEvented::new(stream, handle))
+    {
+        Ok(v) => v,
+        Err(e) => {
+            error!("{}\r", e);
+            return Box::new(futures::future::err(e.into()));
+        }
+    };
+    builder.async_connect_on(Box::new(stream))
+}
+
+fn connect_docker(
+    stream: docker::Stream,
+    is_tty: bool,
+    stdin: futures::sync::mpsc::Receiver<Vec<u8>>,
+) -> ProgramStatus {
+    let mut writer = match stream.try_clone() {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("{}\r", e);
+            return ProgramStatus::Failure;
+        }
+    };
+    std::thread::spawn(move || {
+        for bytes in stdin.wait() {
+            match bytes {
+                // stdin has ended, the command's output still follows
+                Ok(ref v) if v.is_empty() => {
+                    let _ = writer.shutdown(std::net::Shutdown::Write);
+                    return;
+                }
+                Ok(ref v) if writer.write_all(v).is_ok() => (),
+                _ => break,
+            }
+        }
+        // unblocks the reader below when the escape sequence terminates
+        let _ = writer.shutdown(std::net::Shutdown::Both);
+    });
+
+    let mut reader = stream;
+    match docker::forward_output(&mut reader, is_tty) {
+        Ok(_) => debug3!("connection closed successfully"),
+        Err(e) => debug3!("connection closed with error {:?}", e),
+    };
+
+    ProgramStatus::Success
+}
+
+fn connect_kubernetes(
+    client: &kubernetes::Client,
+    websocket_url: url::Url,
+    terminal_size: Option<kubernetes::TerminalSize>,
+    stdin: futures::sync::mpsc::Receiver<Vec<u8>>,
+) -> ProgramStatus {
+    let tls = match client.tls_connector() {
+        Ok(v) => v,
+        Err(e) => {
+            fatal!("{}\r", e);
+            return ProgramStatus::Failure;
+        }
+    };
+    let mut core = tokio_core::reactor::Core::new().unwrap();
+    let mut stdout = std::io::stdout();
+    let mut stderr = std::io::stderr();
+
+    let mut initial = Vec::new();
+    if let Some(ref size) = terminal_size {
+        let data = serde_json::to_vec(size).expect("failed to construct json");
+        initial.push(websocket::OwnedMessage::Binary(
+            kubernetes::write_channel(kubernetes::RESIZE_CHANNEL, &data),
+        ));
+    }
+
+    debug!("Connecting to websocket\r");
+    let runner = websocket::ClientBuilder::from_url(&websocket_url)
+        .add_protocol(kubernetes::PROTOCOL)
+        .custom_headers(&client.websocket_headers())
+        .async_connect(tls, &core.handle())
+        .and_then(|(duplex, _)| {
+            let (sink, stream) = duplex.split();
+            and_select::new(
+                stream.filter_map(|message| match message {
+                    websocket::OwnedMessage::Binary(data) => {
+                        match kubernetes::read_channel(&data) {
+                            kubernetes::Output::Stdout(bytes) => {
+                                stdout.write_all(bytes).unwrap();
+                                stdout.flush().unwrap();
+                            }
+                            kubernetes::Output::Stderr(bytes) => {
+                                stderr.write_all(bytes).unwrap();
+                                stderr.flush().unwrap();
+                            }
+                            kubernetes::Output::Error(message) => error!("{}\r", message),
+                            kubernetes::Output::Ignored => (),
+                        };
+                        None
+                    }
+                    websocket::OwnedMessage::Close(e) => Some(websocket::OwnedMessage::Close(e)),
+                    websocket::OwnedMessage::Ping(d) => Some(websocket::OwnedMessage::Pong(d)),
+                    _ => None,
+                }),
+                futures::stream::iter_ok(initial).chain(
+                    stdin
+                        .filter(|bytes| !bytes.is_empty())
+                        .map(|bytes| {
+                            websocket::OwnedMessage::Binary(
+                                kubernetes::write_channel(kubernetes::STDIN_CHANNEL, &bytes),
+                            )
+                        })
+                        .map_err(|_| websocket::result::WebSocketError::NoDataAvailable),
+                ),
             ).forward(sink)
         });
 
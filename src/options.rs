@@ -9,9 +9,13 @@ use std::str::FromStr;
 
 use pattern;
 
+// tokens a ProxyCommand accepts
+const PROXY_COMMAND_TOKENS: &'static [char] = &['h', 'p', 'r'];
+
 #[derive(Debug)]
 pub enum BuildError {
-    MissingEnvironment,
+    BadProxyUrl(String),
+    BadWebsocketHost(String),
     MissingHostName,
     MissingService,
     MissingStack,
@@ -21,7 +25,8 @@ pub enum BuildError {
 impl std::error::Error for BuildError {
     fn description(&self) -> &str {
         match *self {
-            BuildError::MissingEnvironment => "no environment provided",
+            BuildError::BadProxyUrl(_) => "bad proxy URL",
+            BuildError::BadWebsocketHost(_) => "bad websocket host",
             BuildError::MissingHostName => "no hostname provided",
             BuildError::MissingService => "no service provided",
             BuildError::MissingStack => "no stack provided",
@@ -40,6 +45,65 @@ impl fmt::Display for BuildError {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthProvider {
+    Auto,
+    AzureAd,
+    GitHub,
+    Ldap,
+    Local,
+    OpenLdap,
+    Shibboleth,
+}
+
+impl AuthProvider {
+    /// Providers that accept a `user:password` code in the token exchange,
+    /// the others need a browser based flow.
+    pub fn accepts_password(&self) -> bool {
+        match self {
+            &AuthProvider::GitHub | &AuthProvider::Shibboleth => false,
+            _ => true,
+        }
+    }
+}
+
+impl Default for AuthProvider {
+    fn default() -> AuthProvider {
+        AuthProvider::Auto
+    }
+}
+
+impl fmt::Display for AuthProvider {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match self {
+            &AuthProvider::Auto => "auto".fmt(fmt),
+            &AuthProvider::AzureAd => "azureadconfig".fmt(fmt),
+            &AuthProvider::GitHub => "githubconfig".fmt(fmt),
+            &AuthProvider::Ldap => "ldapconfig".fmt(fmt),
+            &AuthProvider::Local => "localauthconfig".fmt(fmt),
+            &AuthProvider::OpenLdap => "openldapconfig".fmt(fmt),
+            &AuthProvider::Shibboleth => "shibbolethconfig".fmt(fmt),
+        }
+    }
+}
+
+impl FromStr for AuthProvider {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "auto" => Ok(AuthProvider::Auto),
+            "azuread" | "azureadconfig" => Ok(AuthProvider::AzureAd),
+            "github" | "githubconfig" => Ok(AuthProvider::GitHub),
+            "activedirectory" | "ldap" | "ldapconfig" => Ok(AuthProvider::Ldap),
+            "local" | "localauth" | "localauthconfig" => Ok(AuthProvider::Local),
+            "openldap" | "openldapconfig" => Ok(AuthProvider::OpenLdap),
+            "shibboleth" | "shibbolethconfig" => Ok(AuthProvider::Shibboleth),
+            _ => Err(ParseError),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Container {
     First,
@@ -76,6 +140,42 @@ impl FromStr for Container {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlMaster {
+    Auto,
+    No,
+    Yes,
+}
+
+impl Default for ControlMaster {
+    fn default() -> ControlMaster {
+        ControlMaster::No
+    }
+}
+
+impl fmt::Display for ControlMaster {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match self {
+            &ControlMaster::Auto => "auto".fmt(fmt),
+            &ControlMaster::No => "no".fmt(fmt),
+            &ControlMaster::Yes => "yes".fmt(fmt),
+        }
+    }
+}
+
+impl FromStr for ControlMaster {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "auto" => Ok(ControlMaster::Auto),
+            "no" | "false" => Ok(ControlMaster::No),
+            "yes" | "true" => Ok(ControlMaster::Yes),
+            _ => Err(ParseError),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ParseError;
 
@@ -167,15 +267,26 @@ impl FromStr for LogLevel {
 
 #[derive(Debug, Clone, Copy)]
 pub enum Protocol {
+    Docker,
     Http,
     Https,
+    Kubernetes,
 }
 
 impl Protocol {
     pub fn default_port(&self) -> u16 {
         match self {
+            &Protocol::Docker => 2375,
             &Protocol::Http => 80,
             &Protocol::Https => 443,
+            &Protocol::Kubernetes => 443,
+        }
+    }
+
+    pub fn has_environments(&self) -> bool {
+        match self {
+            &Protocol::Docker | &Protocol::Kubernetes => false,
+            &Protocol::Http | &Protocol::Https => true,
         }
     }
 }
@@ -189,8 +300,10 @@ impl Default for Protocol {
 impl fmt::Display for Protocol {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
         match self {
+            &Protocol::Docker => "docker".fmt(fmt),
             &Protocol::Http => "http".fmt(fmt),
             &Protocol::Https => "https".fmt(fmt),
+            &Protocol::Kubernetes => "k8s".fmt(fmt),
         }
     }
 }
@@ -200,13 +313,40 @@ impl FromStr for Protocol {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_ref() {
+            "docker" => Ok(Protocol::Docker),
             "http" => Ok(Protocol::Http),
             "https" => Ok(Protocol::Https),
+            "k8s" | "kubernetes" => Ok(Protocol::Kubernetes),
             _ => Err(ParseError),
         }
     }
 }
 
+/// A `ProxyCommand`, run for each connection with `%h` and `%p` the host and
+/// port connected to.
+#[derive(Debug, Clone)]
+pub struct ProxyCommand {
+    template: String,
+    tokens: HashMap<char, String>,
+}
+
+impl ProxyCommand {
+    pub fn command(&self, host: &str, port: u16) -> String {
+        let mut tokens = self.tokens.clone();
+        tokens.insert('h', host.to_string());
+        tokens.insert('p', port.to_string());
+        expand(&self.template, PROXY_COMMAND_TOKENS, &tokens).expect(
+            "ProxyCommand tokens were checked when built",
+        )
+    }
+}
+
+impl fmt::Display for ProxyCommand {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        self.template.fmt(fmt)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum RequestTTY {
     Auto,
@@ -246,22 +386,109 @@ impl FromStr for RequestTTY {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StrictHostKeyChecking {
+    AcceptNew,
+    Ask,
+    No,
+    Yes,
+}
+
+impl Default for StrictHostKeyChecking {
+    fn default() -> StrictHostKeyChecking {
+        StrictHostKeyChecking::AcceptNew
+    }
+}
+
+impl fmt::Display for StrictHostKeyChecking {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match self {
+            &StrictHostKeyChecking::AcceptNew => "accept-new".fmt(fmt),
+            &StrictHostKeyChecking::Ask => "ask".fmt(fmt),
+            &StrictHostKeyChecking::No => "no".fmt(fmt),
+            &StrictHostKeyChecking::Yes => "yes".fmt(fmt),
+        }
+    }
+}
+
+impl FromStr for StrictHostKeyChecking {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "accept-new" => Ok(StrictHostKeyChecking::AcceptNew),
+            "ask" => Ok(StrictHostKeyChecking::Ask),
+            "no" | "off" | "false" => Ok(StrictHostKeyChecking::No),
+            "yes" | "true" => Ok(StrictHostKeyChecking::Yes),
+            _ => Err(ParseError),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebsocketProtocol {
+    Ws,
+    Wss,
+}
+
+impl fmt::Display for WebsocketProtocol {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match self {
+            &WebsocketProtocol::Ws => "ws".fmt(fmt),
+            &WebsocketProtocol::Wss => "wss".fmt(fmt),
+        }
+    }
+}
+
+impl FromStr for WebsocketProtocol {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "ws" => Ok(WebsocketProtocol::Ws),
+            "wss" => Ok(WebsocketProtocol::Wss),
+            _ => Err(ParseError),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct OptionsBuilder {
     tokens: HashMap<char, String>,
+    auth_provider: AuthProvider,
+    batch_mode: bool,
+    ca_certificate: Option<String>,
+    cache_ttl: Option<u64>,
+    check_host_name: Option<bool>,
+    client_certificate: Option<String>,
+    client_key: Option<String>,
     container: Container,
+    context: Option<String>,
+    control_master: ControlMaster,
+    control_path: Option<String>,
+    control_persist: Option<u64>,
+    credential_helper: Option<String>,
+    encrypt_keys: bool,
     environment: Option<String>,
     escape_char: Option<char>,
     host_name: Option<String>,
     log_level: LogLevel,
+    number_of_password_prompts: Option<u16>,
     port: Option<u16>,
     protocol: Protocol,
+    proxy_command: Option<String>,
+    proxy_url: Option<String>,
     remote_command: Option<String>,
     request_tty: RequestTTY,
     send_env: Vec<pattern::Pattern>,
+    server_only: bool,
     service: Option<String>,
     stack: Option<String>,
+    strict_host_key_checking: StrictHostKeyChecking,
+    strict_modes: Option<bool>,
     user: Option<String>,
+    websocket_host: Option<String>,
+    websocket_protocol: Option<WebsocketProtocol>,
 }
 
 impl OptionsBuilder {
@@ -271,45 +498,116 @@ impl OptionsBuilder {
             None => users::get_current_username().unwrap_or("root".to_string()),
         };
         self.token('r', user.clone());
-        let environment = expand(
-            &self.environment.ok_or(BuildError::MissingEnvironment)?,
-            &['e', 'S', 's'],
-            &self.tokens,
-        )?;
+        let port = self.port.unwrap_or(self.protocol.default_port());
+        self.token('p', port.to_string());
+        let control_path = match self.control_path {
+            Some(ref v) if v != "none" => Some(expand(&home_path(v), &['h', 'p', 'r'], &self.tokens)?),
+            _ => None,
+        };
+        let environment = match self.environment {
+            Some(ref v) => Some(expand(v, &['e', 'S', 's'], &self.tokens)?),
+            None => None,
+        };
         let host_name = expand(
             &self.host_name.ok_or(BuildError::MissingHostName)?,
             &['h'],
             &self.tokens,
         )?;
+        // HostName may include the path Rancher is served under
+        let (host_name, base_path) = match host_name.find('/') {
+            Some(i) => {
+                let base_path = host_name[i..].trim_right_matches('/').to_string();
+                (host_name[..i].to_string(), base_path)
+            }
+            None => (host_name, String::new()),
+        };
+        // the command connects to the host name given for the host
+        self.tokens.insert('h', host_name.clone());
+        // expanded for each connection, but checked now
+        let proxy_command = match self.proxy_command {
+            Some(ref v) if v != "none" => {
+                expand(v, PROXY_COMMAND_TOKENS, &self.tokens)?;
+                Some(ProxyCommand {
+                    template: v.to_owned(),
+                    tokens: self.tokens.clone(),
+                })
+            }
+            _ => None,
+        };
+        let proxy_url = match self.proxy_url {
+            Some(ref v) if v == "none" => None,
+            Some(ref v) => Some(v.to_owned()),
+            None => proxy_from_env(self.protocol, &host_name),
+        };
+        let proxy_url = match proxy_url {
+            // proxies are often given without the scheme
+            Some(ref v) if !v.contains("://") => Some(parse_proxy_url(&format!("http://{}", v))?),
+            Some(ref v) => Some(parse_proxy_url(v)?),
+            None => None,
+        };
+        let websocket_host = match self.websocket_host {
+            Some(ref v) if v != "none" => {
+                Some(parse_websocket_host(&expand(v, &['h', 'p', 'r'], &self.tokens)?)?)
+            }
+            _ => None,
+        };
+        // Rancher may give ws:// from behind a load balancer ending TLS
+        let websocket_protocol = match (self.websocket_protocol, self.protocol) {
+            (Some(v), _) => Some(v),
+            (None, Protocol::Https) => Some(WebsocketProtocol::Wss),
+            (None, _) => None,
+        };
         let remote_command = expand(
             &self.remote_command.unwrap_or("login -p -f %r".to_string()),
             &['r'],
             &self.tokens,
         )?;
-        let stack = expand(
-            &self.stack.ok_or(BuildError::MissingStack)?,
-            &['e', 'S', 's'],
-            &self.tokens,
-        )?;
-        let service = expand(
-            &self.service.ok_or(BuildError::MissingService)?,
-            &['e', 'S', 's'],
-            &self.tokens,
-        )?;
+        let stack = match self.stack {
+            Some(ref v) => Some(expand(v, &['e', 'S', 's'], &self.tokens)?),
+            // Rancher services can be found without their stack
+            None if self.server_only || self.protocol.has_environments() => None,
+            None => return Err(BuildError::MissingStack),
+        };
+        let service = match self.service {
+            Some(ref v) => expand(v, &['e', 'S', 's'], &self.tokens)?,
+            None if self.server_only => String::new(),
+            None => return Err(BuildError::MissingService),
+        };
         Ok(Options {
+            auth_provider: self.auth_provider,
+            base_path,
+            batch_mode: self.batch_mode,
+            ca_certificate: self.ca_certificate.as_ref().map(|v| home_path(v)),
+            cache_ttl: self.cache_ttl.unwrap_or(3600),
+            check_host_name: self.check_host_name.unwrap_or(true),
+            client_certificate: self.client_certificate.as_ref().map(|v| home_path(v)),
+            client_key: self.client_key.as_ref().map(|v| home_path(v)),
             container: self.container,
+            context: self.context,
+            control_master: self.control_master,
+            control_path,
+            control_persist: self.control_persist.unwrap_or(600),
+            credential_helper: self.credential_helper,
+            encrypt_keys: self.encrypt_keys,
             environment,
             escape_char: self.escape_char,
             host_name,
             log_level: self.log_level,
-            port: self.port.unwrap_or(self.protocol.default_port()),
+            number_of_password_prompts: self.number_of_password_prompts.unwrap_or(3),
+            port,
             protocol: self.protocol,
+            proxy_command,
+            proxy_url,
             remote_command,
             request_tty: self.request_tty,
             send_env: self.send_env,
             service,
             stack,
+            strict_host_key_checking: self.strict_host_key_checking,
+            strict_modes: self.strict_modes.unwrap_or(true),
             user,
+            websocket_host,
+            websocket_protocol,
         })
     }
 
@@ -318,11 +616,76 @@ impl OptionsBuilder {
         self
     }
 
+    pub fn auth_provider<'a>(&'a mut self, auth_provider: AuthProvider) -> &'a mut OptionsBuilder {
+        self.auth_provider = auth_provider;
+        self
+    }
+
+    pub fn batch_mode<'a>(&'a mut self, batch_mode: bool) -> &'a mut OptionsBuilder {
+        self.batch_mode = batch_mode;
+        self
+    }
+
+    pub fn ca_certificate<'a>(&'a mut self, ca_certificate: String) -> &'a mut OptionsBuilder {
+        self.ca_certificate = Some(ca_certificate);
+        self
+    }
+
+    pub fn cache_ttl<'a>(&'a mut self, cache_ttl: u64) -> &'a mut OptionsBuilder {
+        self.cache_ttl = Some(cache_ttl);
+        self
+    }
+
+    pub fn check_host_name<'a>(&'a mut self, check_host_name: bool) -> &'a mut OptionsBuilder {
+        self.check_host_name = Some(check_host_name);
+        self
+    }
+
+    pub fn client_certificate<'a>(&'a mut self, client_certificate: String) -> &'a mut OptionsBuilder {
+        self.client_certificate = Some(client_certificate);
+        self
+    }
+
+    pub fn client_key<'a>(&'a mut self, client_key: String) -> &'a mut OptionsBuilder {
+        self.client_key = Some(client_key);
+        self
+    }
+
     pub fn container<'a>(&'a mut self, container: Container) -> &'a mut OptionsBuilder {
         self.container = container;
         self
     }
 
+    pub fn context<'a>(&'a mut self, context: String) -> &'a mut OptionsBuilder {
+        self.context = Some(context);
+        self
+    }
+
+    pub fn control_master<'a>(&'a mut self, control_master: ControlMaster) -> &'a mut OptionsBuilder {
+        self.control_master = control_master;
+        self
+    }
+
+    pub fn control_path<'a>(&'a mut self, control_path: String) -> &'a mut OptionsBuilder {
+        self.control_path = Some(control_path);
+        self
+    }
+
+    pub fn control_persist<'a>(&'a mut self, control_persist: u64) -> &'a mut OptionsBuilder {
+        self.control_persist = Some(control_persist);
+        self
+    }
+
+    pub fn credential_helper<'a>(&'a mut self, command: String) -> &'a mut OptionsBuilder {
+        self.credential_helper = Some(command);
+        self
+    }
+
+    pub fn encrypt_keys<'a>(&'a mut self, encrypt_keys: bool) -> &'a mut OptionsBuilder {
+        self.encrypt_keys = encrypt_keys;
+        self
+    }
+
     pub fn environment<'a>(&'a mut self, environment: String) -> &'a mut OptionsBuilder {
         self.environment = Some(environment);
         self
@@ -343,6 +706,11 @@ impl OptionsBuilder {
         self
     }
 
+    pub fn number_of_password_prompts<'a>(&'a mut self, prompts: u16) -> &'a mut OptionsBuilder {
+        self.number_of_password_prompts = Some(prompts);
+        self
+    }
+
     pub fn port<'a>(&'a mut self, port: u16) -> &'a mut OptionsBuilder {
         self.port = Some(port);
         self
@@ -353,6 +721,16 @@ impl OptionsBuilder {
         self
     }
 
+    pub fn proxy_command<'a>(&'a mut self, proxy_command: String) -> &'a mut OptionsBuilder {
+        self.proxy_command = Some(proxy_command);
+        self
+    }
+
+    pub fn proxy_url<'a>(&'a mut self, proxy_url: String) -> &'a mut OptionsBuilder {
+        self.proxy_url = Some(proxy_url);
+        self
+    }
+
     pub fn remote_command<'a>(&'a mut self, remote_command: String) -> &'a mut OptionsBuilder {
         self.remote_command = Some(remote_command);
         self
@@ -368,6 +746,13 @@ impl OptionsBuilder {
         self
     }
 
+    /// Don't require an environment, stack, and service, for commands that
+    /// only talk to the server.
+    pub fn server_only<'a>(&'a mut self, server_only: bool) -> &'a mut OptionsBuilder {
+        self.server_only = server_only;
+        self
+    }
+
     pub fn service<'a>(&'a mut self, service: String) -> &'a mut OptionsBuilder {
         self.service = Some(service);
         self
@@ -378,10 +763,83 @@ impl OptionsBuilder {
         self
     }
 
+    pub fn strict_host_key_checking<'a>(
+        &'a mut self,
+        strict_host_key_checking: StrictHostKeyChecking,
+    ) -> &'a mut OptionsBuilder {
+        self.strict_host_key_checking = strict_host_key_checking;
+        self
+    }
+
+    pub fn strict_modes<'a>(&'a mut self, strict_modes: bool) -> &'a mut OptionsBuilder {
+        self.strict_modes = Some(strict_modes);
+        self
+    }
+
     pub fn user<'a>(&'a mut self, user: String) -> &'a mut OptionsBuilder {
         self.user = Some(user);
         self
     }
+
+    pub fn websocket_host<'a>(&'a mut self, websocket_host: String) -> &'a mut OptionsBuilder {
+        self.websocket_host = Some(websocket_host);
+        self
+    }
+
+    pub fn websocket_protocol<'a>(
+        &'a mut self,
+        websocket_protocol: WebsocketProtocol,
+    ) -> &'a mut OptionsBuilder {
+        self.websocket_protocol = Some(websocket_protocol);
+        self
+    }
+}
+
+// paths starting "~/" are relative to the home directory
+fn home_path(path: &str) -> String {
+    if path.starts_with("~/") {
+        let home = std::env::home_dir().unwrap_or(std::path::PathBuf::from("/"));
+        home.join(&path[2..]).to_string_lossy().into_owned()
+    } else {
+        path.to_string()
+    }
+}
+
+// HTTPS_PROXY or HTTP_PROXY, as curl takes them, unless NO_PROXY names the
+// host
+fn proxy_from_env(protocol: Protocol, host: &str) -> Option<String> {
+    let names = match protocol {
+        Protocol::Https => ["HTTPS_PROXY", "https_proxy"],
+        Protocol::Http => ["HTTP_PROXY", "http_proxy"],
+        Protocol::Docker | Protocol::Kubernetes => return None,
+    };
+    let proxy = names.iter().filter_map(|name| std::env::var(name).ok()).find(|v| !v.is_empty())?;
+    let no_proxy = std::env::var("NO_PROXY").or_else(|_| std::env::var("no_proxy")).unwrap_or_default();
+    let excluded = no_proxy.split(',').map(|v| v.trim()).any(|v| {
+        let domain = v.trim_left_matches('.');
+        v == "*" || (!domain.is_empty() && (host == domain || host.ends_with(&format!(".{}", domain))))
+    });
+    if excluded {
+        None
+    } else {
+        Some(proxy)
+    }
+}
+
+fn parse_proxy_url(value: &str) -> Result<url::Url, BuildError> {
+    match url::Url::parse(value) {
+        Ok(ref v) if v.scheme() == "http" && v.host_str().is_some() => Ok(v.clone()),
+        _ => Err(BuildError::BadProxyUrl(value.to_string())),
+    }
+}
+
+// a host name, optionally with a port, but nothing else of a URL
+fn parse_websocket_host(value: &str) -> Result<String, BuildError> {
+    match url::Url::parse(&format!("ws://{}", value)) {
+        Ok(ref v) if v.host_str().is_some() && v.username().is_empty() && v.path() == "/" &&
+            v.query().is_none() && v.fragment().is_none() => Ok(value.to_string()),
+        _ => Err(BuildError::BadWebsocketHost(value.to_string())),
+    }
 }
 
 fn expand(
@@ -412,6 +870,7 @@ fn expand(
     Ok(res.iter().collect())
 }
 
+#[derive(Clone)]
 pub struct Options {
     // pub canonical_domains: Vec<String>,
     // pub canonicalize_fallback_local: bool, // default true
@@ -420,18 +879,33 @@ pub struct Options {
     // pub canonicalize_permitted_cnames: Vec<Rule>,
     // pub connection_attempts: u16, // default 1
     // pub connect_timeout: Option<u16>,
+    pub auth_provider: AuthProvider, // default auto, as reported by the server
+    pub base_path: String, // from HostName, default none
+    pub batch_mode: bool, // -B default false
+    pub ca_certificate: Option<String>, // default the system's
+    pub cache_ttl: u64, // default 3600, 0 disables the cache
+    pub check_host_name: bool, // default true
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>, // default client_certificate
     pub container: Container,
-    pub environment: String,
+    pub context: Option<String>, // default the host name
+    pub control_master: ControlMaster, // default no
+    pub control_path: Option<String>,
+    pub control_persist: u64, // default 600
+    pub credential_helper: Option<String>,
+    pub encrypt_keys: bool, // default false
+    pub environment: Option<String>, // default from the API key's account
     pub escape_char: Option<char>, // -e default "~"
     pub host_name: String,
     // pub ignore_unknown: Vec<Pattern>,
     // pub local_command: Option<String>,
     pub log_level: LogLevel, // -q quiet -v verbose -vv debug -vvv debug2, default info
-    // pub number_of_password_prompts: u16, // default 3
+    pub number_of_password_prompts: u16, // default 3
     // pub permit_local_command: bool, // default false
     pub port: u16, // -p default protocol.default_port()
     pub protocol: Protocol, // default https
-    // pub proxy_command: Option<String>,
+    pub proxy_command: Option<ProxyCommand>, // takes precedence over proxy_url
+    pub proxy_url: Option<url::Url>, // default from HTTPS_PROXY or HTTP_PROXY
     // pub proxy_use_fdpass: bool, // default false
     pub remote_command: String,
     pub request_tty: RequestTTY, // -T no -t yes -tt force, default auto
@@ -439,25 +913,42 @@ pub struct Options {
     // pub server_alive_count_max: u16, // default 3
     // pub server_alive_interval: u16, // default 0
     pub service: String,
-    pub stack: String, // default stack
+    pub stack: Option<String>, // default search all stacks for the service
+    pub strict_host_key_checking: StrictHostKeyChecking, // default accept-new
+    pub strict_modes: bool, // default true
     // pub tcp_keep_alive: bool, // default true, 7200
     pub user: String, // -l
+    pub websocket_host: Option<String>, // default the host Rancher gives
+    pub websocket_protocol: Option<WebsocketProtocol>, // default wss with https
 }
 
 impl Options {
     pub fn url(&self) -> url::Url {
         if self.port == self.protocol.default_port() {
-            url::Url::parse(&format!("{}://{}", self.protocol, self.host_name)).unwrap()
+            url::Url::parse(&format!(
+                "{}://{}{}/",
+                self.protocol,
+                self.host_name,
+                self.base_path
+            )).unwrap()
         } else {
             url::Url::parse(&format!(
-                "{}://{}:{}",
+                "{}://{}:{}{}/",
                 self.protocol,
                 self.host_name,
-                self.port
+                self.port,
+                self.base_path
             )).unwrap()
         }
     }
 
+    /// Names the server's API key file, including any base path, with its
+    /// slashes encoded, so installs under different paths keep their own.
+    pub fn api_key_name(&self) -> String {
+        let base_path = self.base_path.replace('%', "%25").replace('/', "%2F");
+        format!("{}{}", self.host_with_port(), base_path)
+    }
+
     pub fn host_with_port(&self) -> String {
         if self.port == self.protocol.default_port() {
             format!("{}", self.host_name)
@@ -471,18 +962,73 @@ impl fmt::Display for Options {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
         write!(fmt, "protocol {}\n", self.protocol)?;
         write!(fmt, "user {}\n", self.user)?;
-        write!(fmt, "hostname {}\n", self.host_name)?;
+        write!(fmt, "hostname {}{}\n", self.host_name, self.base_path)?;
         write!(fmt, "port {}\n", self.port)?;
-        write!(fmt, "environment {}\n", self.environment)?;
-        write!(fmt, "stack {}\n", self.stack)?;
+        write!(fmt, "authprovider {}\n", self.auth_provider)?;
+        if let Some(ref v) = self.environment {
+            write!(fmt, "environment {}\n", v)?;
+        }
+        if let Some(ref v) = self.stack {
+            write!(fmt, "stack {}\n", v)?;
+        }
         write!(fmt, "service {}\n", self.service)?;
         write!(fmt, "container {}\n", self.container)?;
+        if let Some(ref v) = self.context {
+            write!(fmt, "context {}\n", v)?;
+        }
+        write!(fmt, "controlmaster {}\n", self.control_master)?;
+        match self.control_path {
+            Some(ref v) => write!(fmt, "controlpath {}\n", v)?,
+            None => write!(fmt, "controlpath none\n")?,
+        }
+        write!(fmt, "controlpersist {}\n", self.control_persist)?;
+        write!(fmt, "batchmode {}\n", if self.batch_mode { "yes" } else { "no" })?;
+        if let Some(ref v) = self.ca_certificate {
+            write!(fmt, "cacertificate {}\n", v)?;
+        }
+        write!(fmt, "cachettl {}\n", self.cache_ttl)?;
+        write!(fmt, "checkhostname {}\n", if self.check_host_name { "yes" } else { "no" })?;
+        if let Some(ref v) = self.client_certificate {
+            write!(fmt, "clientcertificate {}\n", v)?;
+        }
+        if let Some(ref v) = self.client_key {
+            write!(fmt, "clientkey {}\n", v)?;
+        }
+        if let Some(ref v) = self.credential_helper {
+            write!(fmt, "credentialhelper {}\n", v)?;
+        }
+        write!(fmt, "encryptkeys {}\n", if self.encrypt_keys { "yes" } else { "no" })?;
+        write!(fmt, "stricthostkeychecking {}\n", self.strict_host_key_checking)?;
+        write!(fmt, "strictmodes {}\n", if self.strict_modes { "yes" } else { "no" })?;
         match self.escape_char {
             Some(ref v) => write!(fmt, "escapechar {}\n", v)?,
             None => write!(fmt, "escapechar none\n")?,
         }
         write!(fmt, "loglevel {}\n", self.log_level)?;
+        write!(fmt, "numberofpasswordprompts {}\n", self.number_of_password_prompts)?;
         write!(fmt, "remotecommand {}\n", self.remote_command)?;
+        match self.proxy_command {
+            Some(ref v) => write!(fmt, "proxycommand {}\n", v)?,
+            None => write!(fmt, "proxycommand none\n")?,
+        }
+        match self.proxy_url {
+            // leaving out any credentials, as they'd be shown in plain text
+            Some(ref v) => {
+                let mut redacted = v.clone();
+                let _ = redacted.set_username("");
+                let _ = redacted.set_password(None);
+                write!(fmt, "proxyurl {}\n", redacted)?
+            }
+            None => write!(fmt, "proxyurl none\n")?,
+        }
+        match self.websocket_host {
+            Some(ref v) => write!(fmt, "websockethost {}\n", v)?,
+            None => write!(fmt, "websockethost none\n")?,
+        }
+        match self.websocket_protocol {
+            Some(ref v) => write!(fmt, "websocketprotocol {}\n", v)?,
+            None => write!(fmt, "websocketprotocol none\n")?,
+        }
         write!(fmt, "requesttty {}\n", self.request_tty)?;
         for pattern in &self.send_env {
             write!(fmt, "sendenv {}\n", pattern)?;
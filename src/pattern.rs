@@ -30,6 +30,14 @@ pub struct Pattern {
 }
 
 impl Pattern {
+    /// Whether the pattern only matches itself, having no wildcards.
+    pub fn is_literal(&self) -> bool {
+        self.tokens.iter().all(|t| match *t {
+            Token::Char(_) => true,
+            _ => false,
+        })
+    }
+
     pub fn matches(&self, string: &str) -> bool {
         self.do_match(&string.chars().collect::<Vec<char>>(), 0, 0)
     }
@@ -127,6 +135,17 @@ impl PatternList {
         }
         false
     }
+
+    /// The patterns without wildcards that the list matches.
+    pub fn literals(&self) -> Vec<String> {
+        self.patterns
+            .iter()
+            .filter_map(|entry| match entry {
+                &PatternListEntry::Positive(ref p) if p.is_literal() => Some(p.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 impl Default for PatternList {
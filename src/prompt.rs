@@ -1,6 +1,8 @@
 use std;
 use std::io::Write;
 
+extern crate rpassword;
+
 pub fn prompt_with_default(prompt: &str, default: Option<String>) -> std::io::Result<String> {
     let mut stdout = std::io::stdout();
     let mut result = String::new();
@@ -8,9 +10,14 @@ pub fn prompt_with_default(prompt: &str, default: Option<String>) -> std::io::Re
         Some(ref v) => format!("{} ({}): ", prompt, v),
         None => format!("{}: ", prompt),
     };
-    write!(stdout, "{}", prompt)?;
-    stdout.flush()?;
-    std::io::stdin().read_line(&mut result)?;
+    match askpass(&prompt) {
+        Some(v) => result = v?,
+        None => {
+            write!(stdout, "{}", prompt)?;
+            stdout.flush()?;
+            std::io::stdin().read_line(&mut result)?;
+        }
+    };
     if result.chars().last() == Some('\n') {
         result.pop();
     }
@@ -23,10 +30,45 @@ pub fn prompt_with_default(prompt: &str, default: Option<String>) -> std::io::Re
     }
 }
 
-pub fn user_choice<T: std::fmt::Display>(choices: &[T]) -> std::io::Result<&T> {
+pub fn prompt_password(prompt: &str) -> std::io::Result<String> {
+    let prompt = format!("{}: ", prompt);
+    match askpass(&prompt) {
+        Some(v) => Ok(v?.trim_right_matches(|c| c == '\n' || c == '\r').to_string()),
+        None => rpassword::prompt_password_stdout(&prompt),
+    }
+}
+
+/// Like `SSH_ASKPASS`, when `RSH_ASKPASS` is set the program it names is run
+/// with the prompt as its argument and its output taken as the answer.
+fn askpass(prompt: &str) -> Option<std::io::Result<String>> {
+    let program = match std::env::var("RSH_ASKPASS") {
+        Ok(ref v) if !v.is_empty() => v.to_owned(),
+        _ => return None,
+    };
+    debug2!("Running {} for {}", program, prompt.trim());
+    let output = match std::process::Command::new(&program)
+        .arg(prompt)
+        .stdin(std::process::Stdio::null())
+        .output() {
+        Ok(v) => v,
+        Err(e) => return Some(Err(e)),
+    };
+    if !output.status.success() {
+        return Some(Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{} exited with {}", program, output.status),
+        )));
+    }
+    Some(Ok(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+pub fn user_choice<'a, T: std::fmt::Display>(
+    title: &str,
+    choices: &'a [T],
+) -> std::io::Result<&'a T> {
     let mut stdout = std::io::stdout();
     let mut i = 0;
-    write!(stdout, "Select a container:\n")?;
+    write!(stdout, "{}:\n", title)?;
     for choice in choices {
         i += 1;
         write!(stdout, "  {}. {}\n", i, choice)?;
@@ -0,0 +1,361 @@
+extern crate base64;
+extern crate mio;
+extern crate nix;
+extern crate openssl;
+extern crate url;
+
+use std;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use self::mio::unix::EventedFd;
+use self::nix::poll::{poll, EventFlags, PollFd};
+use self::nix::sys::signal::{kill, Signal};
+use self::nix::unistd::Pid;
+use self::openssl::ssl::SslStream;
+use self::url::percent_encoding::percent_decode;
+
+use options::ProxyCommand;
+
+// longest response head accepted from a proxy
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Opens a connection to `host`:`port` tunnelled through the HTTP proxy at
+/// `proxy`, authenticating with any user and password in its URL.
+pub fn connect(proxy: &url::Url, host: &str, port: u16) -> std::io::Result<TcpStream> {
+    let (proxy_host, proxy_port) = proxy_address(proxy);
+    debug!("Connecting to {}:{} through proxy {}:{}\r", host, port, proxy_host, proxy_port);
+    let mut stream = TcpStream::connect((proxy_host, proxy_port))?;
+
+    let mut request = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n", host, port);
+    if let Some(v) = authorization(proxy) {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", v));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    let head = read_head(&mut stream)?;
+    let status = head.lines().next().unwrap_or("");
+    debug2!("Proxy responded {}\r", status);
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(stream),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("proxy refused to connect: {}", status),
+        )),
+    }
+}
+
+/// Opens a connection to the HTTP proxy at `proxy` itself, for plain http
+/// requests, which are sent to it whole rather than through a tunnel.
+pub fn forward(proxy: &url::Url) -> std::io::Result<Connection> {
+    let (host, port) = proxy_address(proxy);
+    debug!("Connecting to proxy {}:{}", host, port);
+    Ok(TcpStream::connect((host, port))?.into())
+}
+
+/// Where connections to servers are made.
+#[derive(Clone, Debug)]
+pub enum Upstream {
+    /// A command whose stdin and stdout are connected to the server.
+    Command(ProxyCommand),
+    /// The server itself.
+    Direct,
+    /// An HTTP proxy, which any user and password in its URL authenticate to.
+    Proxy(url::Url),
+}
+
+impl Upstream {
+    /// Opens a connection to `host`:`port`, which carries whatever is sent
+    /// on it as it is, TLS included.
+    pub fn connect(&self, host: &str, port: u16) -> std::io::Result<Connection> {
+        match *self {
+            Upstream::Command(ref command) => {
+                let (stream, child) = spawn(&command.command(host, port))?;
+                Ok(Connection {
+                    stream: Stream::Unix(stream),
+                    child: Some(child),
+                })
+            }
+            Upstream::Direct => {
+                debug!("Connecting to {}:{}\r", host, port);
+                Ok(TcpStream::connect((host, port))?.into())
+            }
+            Upstream::Proxy(ref proxy) => Ok(connect(proxy, host, port)?.into()),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match *self {
+            Upstream::Command(ref command) => write!(fmt, "command {}", command),
+            Upstream::Direct => write!(fmt, "direct connections"),
+            // leaving out any credentials
+            Upstream::Proxy(ref proxy) => {
+                let (host, port) = proxy_address(proxy);
+                write!(fmt, "proxy {}:{}", host, port)
+            }
+        }
+    }
+}
+
+/// A connection to a server, through whatever `Upstream` says, or the plain
+/// end of a `bridge`.
+#[derive(Debug)]
+pub struct Connection {
+    stream: Stream,
+    // the proxy command at the other end, hung up on once it's closed
+    child: Option<Child>,
+}
+
+#[derive(Debug)]
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref s) => s.set_nonblocking(nonblocking),
+            Stream::Unix(ref s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Gives up reading or writing after `timeout`.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref s) => s.set_read_timeout(timeout).and_then(|_| s.set_write_timeout(timeout)),
+            Stream::Unix(ref s) => s.set_read_timeout(timeout).and_then(|_| s.set_write_timeout(timeout)),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref s) => s.shutdown(how),
+            Stream::Unix(ref s) => s.shutdown(how),
+        }
+    }
+}
+
+impl From<TcpStream> for Connection {
+    fn from(stream: TcpStream) -> Connection {
+        Connection {
+            stream: Stream::Tcp(stream),
+            child: None,
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.stream {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.stream {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref mut s) => s.flush(),
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        match self.stream {
+            Stream::Tcp(ref s) => s.as_raw_fd(),
+            Stream::Unix(ref s) => s.as_raw_fd(),
+        }
+    }
+}
+
+// so tokio's reactor can drive the websocket on it
+impl mio::Evented for Connection {
+    fn register(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> std::io::Result<()> {
+        EventedFd(&self.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready, opts: mio::PollOpt) -> std::io::Result<()> {
+        EventedFd(&self.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> std::io::Result<()> {
+        EventedFd(&self.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            // hung up on as ssh does, as not every command exits at the end
+            // of its input, then waited for out of the way
+            let _ = self.shutdown(Shutdown::Both);
+            let _ = kill(Pid::from_raw(child.id() as i32), Signal::SIGHUP);
+            std::thread::spawn(move || child.wait());
+        }
+    }
+}
+
+/// Gives the plain end of a connection whose data is copied to and from
+/// `stream` by a thread of its own, for the websocket, which runs on tokio's
+/// reactor once the TLS handshake has checked the server's certificate.
+pub fn bridge(stream: SslStream<Connection>) -> std::io::Result<Connection> {
+    let (local, remote) = UnixStream::pair()?;
+    let remote = Connection {
+        stream: Stream::Unix(remote),
+        child: None,
+    };
+    std::thread::spawn(move || if let Err(e) = splice(stream, remote) {
+        debug!("TLS connection failed: {}\r", e);
+    });
+    Ok(Connection {
+        stream: Stream::Unix(local),
+        child: None,
+    })
+}
+
+// runs `command` with its stdin and stdout connected to the returned
+// socket, as ssh does with a ProxyCommand
+fn spawn(command: &str) -> std::io::Result<(UnixStream, Child)> {
+    debug!("Executing proxy command: {}\r", command);
+    let (local, remote) = UnixStream::pair()?;
+    let stdin = unsafe { Stdio::from_raw_fd(remote.try_clone()?.into_raw_fd()) };
+    let stdout = unsafe { Stdio::from_raw_fd(remote.into_raw_fd()) };
+    let child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(format!("exec {}", command))
+        .stdin(stdin)
+        .stdout(stdout)
+        .spawn()?;
+    Ok((local, child))
+}
+
+// copies between the TLS connection and the plain one until either is
+// closed, in one thread as the TLS connection can't be read in one while
+// written in another
+fn splice(mut tls: SslStream<Connection>, mut plain: Connection) -> std::io::Result<()> {
+    tls.get_ref().set_nonblocking(true)?;
+    plain.set_nonblocking(true)?;
+    let mut buffer = [0; 16 * 1024];
+    while copy_available(&mut tls, &mut plain, &mut buffer)? && copy_available(&mut plain, &mut tls, &mut buffer)? {
+        // anything already decrypted is copied without waiting for more
+        if tls.ssl().pending() == 0 {
+            wait(&[tls.get_ref().as_raw_fd(), plain.as_raw_fd()], EventFlags::POLLIN)?;
+        }
+    }
+    Ok(())
+}
+
+// copies what can be read from `from` without blocking to `to`, giving
+// whether `from` is still open
+fn copy_available<R: Read, W: WriteWaiting>(from: &mut R, to: &mut W, buffer: &mut [u8]) -> std::io::Result<bool> {
+    loop {
+        match from.read(buffer) {
+            Ok(0) => return Ok(false),
+            Ok(n) => to.write_waiting(&buffer[..n])?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(true),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+    }
+}
+
+// a nonblocking connection written whole, waiting whenever it can't take
+// any more
+trait WriteWaiting {
+    fn write_waiting(&mut self, data: &[u8]) -> std::io::Result<()>;
+}
+
+impl WriteWaiting for Connection {
+    fn write_waiting(&mut self, mut data: &[u8]) -> std::io::Result<()> {
+        while !data.is_empty() {
+            match self.write(data) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => data = &data[n..],
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    wait(&[self.as_raw_fd()], EventFlags::POLLOUT)?
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(())
+    }
+}
+
+impl WriteWaiting for SslStream<Connection> {
+    fn write_waiting(&mut self, mut data: &[u8]) -> std::io::Result<()> {
+        while !data.is_empty() {
+            match self.ssl_write(data) {
+                Ok(n) => data = &data[n..],
+                Err(openssl::ssl::Error::WantRead(_)) => wait(&[self.get_ref().as_raw_fd()], EventFlags::POLLIN)?,
+                Err(openssl::ssl::Error::WantWrite(_)) => wait(&[self.get_ref().as_raw_fd()], EventFlags::POLLOUT)?,
+                Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+            };
+        }
+        Ok(())
+    }
+}
+
+// blocks until any of `fds` is ready for `events`
+fn wait(fds: &[RawFd], events: EventFlags) -> std::io::Result<()> {
+    let mut fds: Vec<PollFd> = fds.iter().map(|fd| PollFd::new(*fd, events)).collect();
+    match poll(&mut fds, -1) {
+        Ok(_) | Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => Ok(()),
+        Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+    }
+}
+
+fn proxy_address(proxy: &url::Url) -> (&str, u16) {
+    (proxy.host_str().unwrap_or(""), proxy.port_or_known_default().unwrap_or(80))
+}
+
+/// The Proxy-Authorization header for any user and password in the URL of
+/// `proxy`.
+pub fn authorization(proxy: &url::Url) -> Option<String> {
+    if proxy.username().is_empty() {
+        return None;
+    }
+    let decode = |s: &str| percent_decode(s.as_bytes()).decode_utf8_lossy().into_owned();
+    let credentials = format!(
+        "{}:{}",
+        decode(proxy.username()),
+        decode(proxy.password().unwrap_or(""))
+    );
+    Some(format!("Basic {}", base64::encode(&credentials)))
+}
+
+// reads a byte at a time up to the blank line ending the head, as whatever
+// follows belongs to the tunnel
+fn read_head<R: Read>(reader: &mut R) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if reader.read(&mut byte)? == 0 || head.len() >= MAX_HEAD {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "malformed HTTP head",
+            ));
+        }
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
@@ -1,22 +1,39 @@
-extern crate reqwest;
 extern crate url;
 extern crate serde;
+extern crate serde_json;
 extern crate url_serde;
 
 use std;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::fmt;
 
+use cache::Cache;
+use options::{AuthProvider, WebsocketProtocol};
+use http;
+
+// name given to the API keys rsh creates, to tell them apart from any others
+const API_KEY_NAME: &'static str = "rsh";
+
 #[derive(Debug)]
 pub enum Error {
+    // the configured provider, then the server's
+    AuthProviderMismatch(AuthProvider, AuthProvider),
+    BadCredentials,
+    BadWebsocketUrl(String),
     Empty,
-    HttpError(reqwest::Error),
+    HttpError(http::Error),
+    // the status code and reason
+    HttpStatus(u16, String),
+    NotFound,
+    PromptError(std::io::Error),
     Unauthorized,
+    UnsupportedAuthProvider(String),
 }
 
-impl From<reqwest::Error> for Error {
-    fn from(err: reqwest::Error) -> Error {
+impl From<http::Error> for Error {
+    fn from(err: http::Error) -> Error {
         Error::HttpError(err)
     }
 }
@@ -24,15 +41,23 @@ impl From<reqwest::Error> for Error {
 impl StdError for Error {
     fn description(&self) -> &str {
         match *self {
+            Error::AuthProviderMismatch(_, _) => "auth provider mismatch",
+            Error::BadCredentials => "bad credentials",
+            Error::BadWebsocketUrl(_) => "bad websocket URL",
             Error::Empty => "empty",
             Error::HttpError(ref err) => err.description(),
+            Error::HttpStatus(_, _) => "unexpected response",
+            Error::NotFound => "not found",
+            Error::PromptError(ref err) => err.description(),
             Error::Unauthorized => "unauthorized",
+            Error::UnsupportedAuthProvider(_) => "unsupported auth provider",
         }
     }
 
     fn cause(&self) -> Option<&StdError> {
         match *self {
             Error::HttpError(ref err) => Some(err as &StdError),
+            Error::PromptError(ref err) => Some(err as &StdError),
             _ => None,
         }
     }
@@ -40,7 +65,21 @@ impl StdError for Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
-        self.description().fmt(fmt)
+        match *self {
+            Error::AuthProviderMismatch(configured, server) => {
+                write!(fmt, "the Rancher server uses auth provider {} not {}", server, configured)
+            }
+            Error::BadWebsocketUrl(ref url) => write!(fmt, "bad websocket URL {}", url),
+            Error::HttpError(ref err) => err.fmt(fmt),
+            Error::HttpStatus(status, ref reason) => {
+                write!(fmt, "the Rancher server responded {} {}", status, reason)
+            }
+            Error::PromptError(ref err) => write!(fmt, "couldn't ask for a user and password: {}", err),
+            Error::UnsupportedAuthProvider(ref name) => {
+                write!(fmt, "unsupported auth provider {}", name)
+            }
+            _ => self.description().fmt(fmt),
+        }
     }
 }
 
@@ -48,6 +87,39 @@ impl fmt::Display for Error {
 struct Collection<T> {
     data: Vec<T>,
     pagination: Option<Pagination>,
+    // the filters the server applied, with null for those it supports but
+    // weren't given
+    #[serde(default)]
+    filters: HashMap<String, serde_json::Value>,
+}
+
+// collection items that can be looked up by name
+trait Named {
+    fn name(&self) -> &str;
+}
+
+// results fetched through a cached link, which are empty when the link is for
+// something since removed and recreated
+trait Fetched {
+    fn is_empty(&self) -> bool;
+}
+
+impl<T> Fetched for Vec<T> {
+    fn is_empty(&self) -> bool {
+        Vec::is_empty(self)
+    }
+}
+
+impl Fetched for url::Url {
+    fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+impl Fetched for Stack {
+    fn is_empty(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -72,25 +144,83 @@ struct Index {
 
 #[derive(Debug, Deserialize)]
 struct Project {
+    id: String,
     name: String,
+    state: String,
     links: HashMap<String, url_serde::Serde<url::Url>>,
 }
 
+impl Named for Project {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
 #[derive(Debug, Deserialize)]
+struct UserPreference {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct Stack {
     name: String,
+    state: String,
+    #[serde(default)]
+    service_ids: Vec<String>,
     links: HashMap<String, url_serde::Serde<url::Url>>,
 }
 
+impl Named for Stack {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct Service {
     name: String,
+    state: String,
+    #[serde(default)]
+    instance_ids: Vec<String>,
     links: HashMap<String, url_serde::Serde<url::Url>>,
 }
 
+impl Named for Service {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// An environment, stack, service, or container as listed by `Client::list`,
+/// with the number of stacks, services, or containers below it.
+#[derive(Debug, Serialize)]
+pub struct Listing {
+    pub name: String,
+    pub state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+}
+
+/// Where a service is, as `environment/stack/service`.
+#[derive(Debug)]
+pub struct ServicePath {
+    pub environment: String,
+    pub stack: String,
+    pub service: String,
+}
+
+impl fmt::Display for ServicePath {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}/{}/{}", self.environment, self.stack, self.service)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Container {
     name: String,
+    state: String,
     pub actions: HashMap<String, url_serde::Serde<url::Url>>,
 }
 
@@ -100,6 +230,12 @@ impl fmt::Display for Container {
     }
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenInfo {
+    auth_provider: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct TokenRequest {
@@ -122,13 +258,64 @@ struct ApiKeyRequest {
     description: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ApiKey {
     pub public_value: String,
     secret_value: String,
 }
 
+impl ApiKey {
+    pub fn new(public_value: String, secret_value: String) -> ApiKey {
+        ApiKey {
+            public_value,
+            secret_value,
+        }
+    }
+
+    pub fn secret_value(&self) -> &str {
+        &self.secret_value
+    }
+}
+
+/// An API key as listed by the server, without its secret value.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyInfo {
+    name: Option<String>,
+    pub public_value: String,
+    pub state: String,
+    pub created: Option<String>,
+    #[serde(rename = "createdTS")]
+    created_ts: Option<u64>, // milliseconds since the epoch
+    links: HashMap<String, url_serde::Serde<url::Url>>,
+}
+
+impl ApiKeyInfo {
+    /// Whether the key is no longer active or was created longer than
+    /// `max_age` ago. Keys without a creation time are only stale when
+    /// inactive.
+    pub fn is_stale(&self, max_age: std::time::Duration) -> bool {
+        if self.state != "active" {
+            return true;
+        }
+        let created = match self.created_ts {
+            Some(v) => std::time::UNIX_EPOCH + std::time::Duration::from_millis(v),
+            None => return false,
+        };
+        match std::time::SystemTime::now().duration_since(created) {
+            Ok(age) => age > max_age,
+            Err(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for ApiKeyInfo {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        self.public_value.fmt(fmt)
+    }
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ContainerExec {
@@ -149,7 +336,7 @@ impl ContainerExec {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct HostAccess {
     token: String,
     #[serde(with = "url_serde")]
@@ -157,64 +344,160 @@ pub struct HostAccess {
 }
 
 impl HostAccess {
-    pub fn authed_url(&self) -> url::Url {
+    /// The websocket's URL with its token, at `host` instead of the one
+    /// Rancher gave, and using `protocol`, when they're given.
+    pub fn authed_url(&self, host: Option<&str>, protocol: Option<WebsocketProtocol>) -> Result<url::Url, Error> {
         let mut copy = self.url.clone();
+        if let Some(protocol) = protocol {
+            let scheme = protocol.to_string();
+            // a port Rancher gives is for its own protocol, e.g. a load
+            // balancer's plain http one, so the new protocol's is used
+            if copy.scheme() != scheme {
+                let bad_url = || Error::BadWebsocketUrl(self.url.to_string());
+                copy.set_port(None).map_err(|_| bad_url())?;
+                copy.set_scheme(&scheme).map_err(|_| bad_url())?;
+            }
+        }
+        if let Some(host) = host {
+            // the port is the one given with the host, or the protocol's
+            let address = format!("{}://{}", copy.scheme(), host);
+            let bad_url = || Error::BadWebsocketUrl(address.clone());
+            let given = url::Url::parse(&address).map_err(|_| bad_url())?;
+            copy.set_host(given.host_str()).map_err(|_| bad_url())?;
+            copy.set_port(given.port()).map_err(|_| bad_url())?;
+        }
         copy.query_pairs_mut().append_pair("token", &self.token);
-        copy
+        Ok(copy)
     }
 }
 
 pub struct Client {
-    http: reqwest::Client,
-    pub api_key: Option<ApiKey>,
+    http: http::Client,
+    api_key: RefCell<Option<ApiKey>>,
+    // tried in turn when the server rejects the current API key
+    other_api_keys: RefCell<Box<Iterator<Item = ApiKey>>>,
+    reauthenticate: Option<Box<Fn(&Client) -> Result<ApiKey, Error>>>,
+    reauthenticating: Cell<bool>,
+    cache: Option<Cache>,
 }
 
 impl Client {
-    pub fn new() -> Client {
+    /// A client sending its requests with `http`.
+    pub fn new(http: http::Client) -> Client {
         Client {
-            http: reqwest::Client::new(),
-            api_key: None,
+            http,
+            api_key: RefCell::new(None),
+            other_api_keys: RefCell::new(Box::new(std::iter::empty())),
+            reauthenticate: None,
+            reauthenticating: Cell::new(false),
+            cache: None,
         }
     }
 
-    pub fn ldap_auth(&mut self, url: &url::Url, user: &str, password: &str) -> Result<(), Error> {
-        let mut token_url = url.clone();
-        token_url.set_path("/v2-beta/token");
+    pub fn api_key(&self) -> Option<ApiKey> {
+        self.api_key.borrow().clone()
+    }
+
+    pub fn set_api_key(&self, api_key: Option<ApiKey>) {
+        *self.api_key.borrow_mut() = api_key;
+    }
+
+    /// Uses the first of `api_keys`, moving on to the next whenever the server
+    /// rejects one, before getting a new key.
+    pub fn set_api_keys<I>(&self, api_keys: I)
+    where
+        I: Iterator<Item = ApiKey> + 'static,
+    {
+        let mut api_keys = Box::new(api_keys);
+        self.set_api_key(api_keys.next());
+        *self.other_api_keys.borrow_mut() = api_keys;
+    }
+
+    /// Keeps the links found by walking from the environment to the service
+    /// in `cache`, so they needn't be searched for again.
+    pub fn use_cache(&mut self, cache: Cache) {
+        self.cache = Some(cache);
+    }
+
+    /// Sets how to get a new API key when the server rejects the current one,
+    /// the rejected request is then retried once with the new key.
+    pub fn on_unauthorized<F>(&mut self, reauthenticate: F)
+    where
+        F: Fn(&Client) -> Result<ApiKey, Error> + 'static,
+    {
+        self.reauthenticate = Some(Box::new(reauthenticate));
+    }
+
+    /// Gets a new API key as if the server had rejected the current one.
+    pub fn authenticate(&self) -> Result<(), Error> {
+        let reauthenticate = match self.reauthenticate {
+            Some(ref f) if !self.reauthenticating.get() => f,
+            _ => return Err(Error::Unauthorized),
+        };
+        debug2!("Received Unauthorized, attempting authentication");
+        self.reauthenticating.set(true);
+        let result = reauthenticate(self);
+        self.reauthenticating.set(false);
+        self.set_api_key(Some(result?));
+        // need to give rancher a tiny bit of time to activate the api keys
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        Ok(())
+    }
+
+    /// Logs in to the server, creating a new API key.
+    pub fn auth(
+        &self,
+        url: &url::Url,
+        provider: AuthProvider,
+        user: &str,
+        password: &str,
+    ) -> Result<ApiKey, Error> {
+        let provider = match (provider, self.auth_provider(url)) {
+            (AuthProvider::Auto, Ok(v)) => v,
+            (AuthProvider::Auto, Err(Error::UnsupportedAuthProvider(name))) => {
+                return Err(Error::UnsupportedAuthProvider(name))
+            }
+            // servers that don't report a provider were always LDAP
+            (AuthProvider::Auto, Err(_)) => AuthProvider::Ldap,
+            (v, Ok(server)) if v != server => return Err(Error::AuthProviderMismatch(v, server)),
+            (v, _) => v,
+        };
+        if !provider.accepts_password() {
+            return Err(Error::UnsupportedAuthProvider(provider.to_string()));
+        }
+        debug!("Authenticating with {}", provider);
+
+        let token_url = api_url(url, "v2-beta/token");
         debug2!("POST {}", &token_url);
-        let mut token_request = self.http.post(token_url);
         let code = format!("{}:{}", user, password);
-        token_request.json(&TokenRequest {
+        let token_request = http::Request::post(token_url).json(&TokenRequest {
             code,
-            auth_provider: String::from("ldapconfig"),
-        });
-        let mut token_response = token_request.send()?;
-        debug3!("{:?}", token_response);
-        if !token_response.status().is_success() {
-            return Err(Error::Empty);
-        }
+            auth_provider: provider.to_string(),
+        })?;
+        let token_response = self.http.send(token_request)?;
+        match token_response.status {
+            401 | 403 => return Err(Error::BadCredentials),
+            status if !token_response.is_success() => {
+                return Err(Error::HttpStatus(status, token_response.reason))
+            }
+            _ => (),
+        };
         let token: Token = token_response.json()?;
 
-        let mut api_key_url = url.clone();
-        api_key_url.set_path("/v2-beta/apikey");
+        let api_key_url = api_url(url, "v2-beta/apikey");
         debug2!("POST {}", &api_key_url);
-        let mut api_key_request = self.http.post(api_key_url);
-        let mut cookie = reqwest::header::Cookie::new();
-        cookie.set("token", token.jwt);
-        api_key_request.header(cookie);
-        api_key_request.json(&ApiKeyRequest {
-            account_id: token.account_id,
-            name: String::from("rsh"),
-            description: String::from("Rancher SHell"),
-        });
-        let mut api_key_response = api_key_request.send()?;
-        debug3!("{:?}", api_key_response);
-        if !api_key_response.status().is_success() {
-            return Err(Error::Empty);
+        let api_key_request = http::Request::post(api_key_url)
+            .header("Cookie", format!("token={}", token.jwt))
+            .json(&ApiKeyRequest {
+                account_id: token.account_id,
+                name: String::from(API_KEY_NAME),
+                description: String::from("Rancher SHell"),
+            })?;
+        let api_key_response = self.http.send(api_key_request)?;
+        if !api_key_response.is_success() {
+            return Err(Error::HttpStatus(api_key_response.status, api_key_response.reason));
         }
-        let api_key = api_key_response.json()?;
-
-        self.api_key = Some(api_key);
-        Ok(())
+        Ok(api_key_response.json()?)
     }
 
     pub fn executeable_containers(
@@ -224,39 +507,371 @@ impl Client {
         stack: &str,
         service: &str,
     ) -> Result<Vec<Container>, Error> {
+        let key = link_key("instances", url, &[environment, stack, service]);
+        let resolve = || {
+            let services_link = self.in_environment(url, environment, "stacks", |stacks_link| {
+                debug!("Searching for stack {}", stack);
+                let stack: Stack = self.find_by_name(stacks_link, stack)?;
+                link(&stack.links, "services")
+            })?;
+            debug!("Searching for service {}", service);
+            let service: Service = self.find_by_name(&services_link, service)?;
+            link(&service.links, "instances")
+        };
+        self.with_link(&key, resolve, |instances_link| {
+            debug!("Searching for executable container");
+            self.filter_collection(instances_link, |c: &Container| {
+                c.actions.get("execute").is_some()
+            })
+        })
+    }
+
+    /// Walks every environment and stack the API key can access for the
+    /// services matching `cond`.
+    pub fn find_services<F>(&self, url: &url::Url, cond: F) -> Result<Vec<ServicePath>, Error>
+    where
+        F: Fn(&ServicePath) -> bool,
+    {
+        let mut found = Vec::new();
+        for project in self.projects(url)? {
+            debug!("Searching environment {}", project.name);
+            let stacks_link = match project.links.get("stacks") {
+                Some(v) => v,
+                None => continue,
+            };
+            for stack in self.filter_collection(stacks_link, |s: &Stack| s.state != "removed")? {
+                let services_link = match stack.links.get("services") {
+                    Some(v) => v,
+                    None => continue,
+                };
+                for service in self.filter_collection(services_link, |s: &Service| s.state != "removed")? {
+                    let path = ServicePath {
+                        environment: project.name.clone(),
+                        stack: stack.name.clone(),
+                        service: service.name,
+                    };
+                    if cond(&path) {
+                        found.push(path);
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Lists the next level below `path`, which may be empty or name an
+    /// environment, stack, and service in turn.
+    pub fn list(&self, url: &url::Url, path: &[String]) -> Result<Vec<Listing>, Error> {
+        if path.is_empty() {
+            let mut listing = Vec::new();
+            for project in self.projects(url)? {
+                // unknown when the environment doesn't link to its stacks
+                let count = match project.links.get("stacks") {
+                    Some(stacks_link) => Some(self.filter_collection(stacks_link, |_: &Stack| true)?.len()),
+                    None => None,
+                };
+                listing.push(Listing {
+                    name: project.name,
+                    state: project.state,
+                    count,
+                });
+            }
+            return Ok(listing);
+        }
+
+        if path.len() == 1 {
+            let stacks = self.in_environment(url, &path[0], "stacks", |stacks_link| {
+                self.filter_collection(stacks_link, |_: &Stack| true)
+            })?;
+            return Ok(
+                stacks
+                    .into_iter()
+                    .map(|s| {
+                        Listing {
+                            count: Some(s.service_ids.len()),
+                            name: s.name,
+                            state: s.state,
+                        }
+                    })
+                    .collect(),
+            );
+        }
+
+        let stack = self.in_environment(url, &path[0], "stacks", |stacks_link| {
+            self.find_by_name::<Stack>(stacks_link, &path[1])
+        })?;
+        let services_link = stack.links.get("services").ok_or(Error::Empty)?;
+        if path.len() == 2 {
+            let services = self.filter_collection(services_link, |_: &Service| true)?;
+            return Ok(
+                services
+                    .into_iter()
+                    .map(|s| {
+                        Listing {
+                            count: Some(s.instance_ids.len()),
+                            name: s.name,
+                            state: s.state,
+                        }
+                    })
+                    .collect(),
+            );
+        }
+
+        let service: Service = self.find_by_name(services_link, &path[2])?;
+        let instances_link = service.links.get("instances").ok_or(Error::Empty)?;
+        let containers = self.filter_collection(instances_link, |_: &Container| true)?;
+        Ok(
+            containers
+                .into_iter()
+                .map(|c| {
+                    Listing {
+                        name: c.name,
+                        state: c.state,
+                        count: None,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// The names of the environments the API key can access.
+    pub fn environments(&self, url: &url::Url) -> Result<Vec<String>, Error> {
+        Ok(self.projects(url)?.into_iter().map(|p| p.name).collect())
+    }
+
+    /// The environment the account last chose as its default in the Rancher
+    /// UI, if any.
+    pub fn default_environment(&self, url: &url::Url) -> Result<Option<String>, Error> {
+        let mut preferences_url =
+            self.index_link(url, "userPreferences", "v2-beta/userpreferences")?;
+        preferences_url.query_pairs_mut().append_pair(
+            "name",
+            "defaultProjectId",
+        );
+        let preferences: Collection<UserPreference> = match self.get(&preferences_url) {
+            Ok(v) => v,
+            // environment API keys have no account preferences
+            Err(Error::HttpError(http::Error::JsonError(_))) |
+            Err(Error::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let project_id = match preferences.data.into_iter().next() {
+            // the value is JSON encoded, e.g. "\"1a5\""
+            Some(p) => serde_json::from_str(&p.value).unwrap_or(p.value),
+            None => return Ok(None),
+        };
+        let projects_link = self.projects_link(url)?;
+        match self.find_in_collection(&projects_link, |p: &Project| p.id == project_id) {
+            Ok(v) => Ok(Some(v.name)),
+            Err(Error::Empty) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The names of the stacks in the environment with a service named
+    /// `service`.
+    pub fn stacks_with_service(
+        &self,
+        url: &url::Url,
+        environment: &str,
+        service: &str,
+    ) -> Result<Vec<String>, Error> {
+        debug!("Searching all stacks for service {}", service);
+        let services = self.in_environment(url, environment, "services", |services_link| {
+            let mut filtered = services_link.clone();
+            filtered
+                .query_pairs_mut()
+                .append_pair("name", service)
+                .append_pair("state_ne", "removed");
+            // checked again in case the server doesn't filter
+            self.filter_collection(&filtered, |s: &Service| {
+                s.name == service && s.state != "removed"
+            })
+        })?;
+        let mut stacks = Vec::new();
+        for service in services {
+            let stack_link = service.links.get("stack").ok_or(Error::Empty)?;
+            let stack: Stack = self.get(stack_link)?;
+            if stack.state != "removed" {
+                stacks.push(stack.name);
+            }
+        }
+        Ok(stacks)
+    }
+
+    /// The API keys rsh has created for the account.
+    pub fn api_keys(&self, url: &url::Url) -> Result<Vec<ApiKeyInfo>, Error> {
+        let api_keys_url = self.index_link(url, "apiKeys", "v2-beta/apikey")?;
+        self.filter_collection(&api_keys_url, |k: &ApiKeyInfo| {
+            k.name.as_ref().map(String::as_ref) == Some(API_KEY_NAME)
+        })
+    }
+
+    pub fn api_key_info(&self, url: &url::Url, public_value: &str) -> Result<ApiKeyInfo, Error> {
+        let api_keys_url = self.index_link(url, "apiKeys", "v2-beta/apikey")?;
+        match self.find_in_collection(
+            &api_keys_url,
+            |k: &ApiKeyInfo| k.public_value == public_value,
+        ) {
+            Err(Error::Empty) => Err(Error::NotFound),
+            result => result,
+        }
+    }
+
+    /// Deletes the API key on the server, it will no longer authenticate.
+    pub fn revoke_api_key(&self, api_key: &ApiKeyInfo) -> Result<(), Error> {
+        let self_link = api_key.links.get("self").ok_or(Error::Empty)?;
+        debug!("Revoking Rancher API key {}", api_key.public_value);
+        self.delete(self_link)
+    }
+
+    /// The auth provider the server reports it is configured with.
+    fn auth_provider(&self, url: &url::Url) -> Result<AuthProvider, Error> {
+        let token_url = api_url(url, "v2-beta/token");
+        // made without the API key, which might be the reason for logging in
+        debug2!("GET {}", &token_url);
+        let response = self.http.send(http::Request::get(token_url))?;
+        if !response.is_success() {
+            return Err(Error::Empty);
+        }
+        let collection: Collection<TokenInfo> = response.json()?;
+        let name = collection
+            .data
+            .into_iter()
+            .filter_map(|t| t.auth_provider)
+            .next()
+            .ok_or(Error::Empty)?;
+        match name.parse() {
+            Ok(v) => Ok(v),
+            Err(_) => Err(Error::UnsupportedAuthProvider(name)),
+        }
+    }
+
+    /// Calls `fetch` with the `name` link, e.g. "stacks", of `environment`.
+    fn in_environment<F, T>(
+        &self,
+        url: &url::Url,
+        environment: &str,
+        name: &str,
+        fetch: F,
+    ) -> Result<T, Error>
+    where
+        F: Fn(&url::Url) -> Result<T, Error>,
+        T: Fetched,
+    {
+        let key = link_key(name, url, &[environment]);
+        let resolve = || {
+            let project = self.project(url, environment)?;
+            link(&project.links, name)
+        };
+        self.with_link(&key, resolve, fetch)
+    }
+
+    /// Calls `fetch` with the cached link for `key`, or the one `resolve`
+    /// finds, caching it. A cached link the server no longer knows, or that
+    /// finds nothing, is resolved again.
+    fn with_link<R, F, T>(&self, key: &str, resolve: R, fetch: F) -> Result<T, Error>
+    where
+        R: FnOnce() -> Result<url::Url, Error>,
+        F: Fn(&url::Url) -> Result<T, Error>,
+        T: Fetched,
+    {
+        let cached = self.cache.as_ref().and_then(|c| c.get::<String>(key)).and_then(
+            |s| url::Url::parse(&s).ok(),
+        );
+        if let Some(ref link) = cached {
+            match fetch(link) {
+                Err(Error::NotFound) => debug!("Cached {} is gone", link),
+                Err(Error::Empty) => debug!("Cached {} found nothing", link),
+                Ok(ref v) if v.is_empty() => debug!("Cached {} found nothing", link),
+                result => return result,
+            };
+            if let Some(ref cache) = self.cache {
+                cache.remove(key);
+            }
+        }
+
+        let link = resolve()?;
+        if let Some(ref cache) = self.cache {
+            if let Err(e) = cache.set(key, &link.as_str()) {
+                debug!("Couldn't cache {}: {}", key, e);
+            }
+        }
+        fetch(&link)
+    }
+
+    fn project(&self, url: &url::Url, environment: &str) -> Result<Project, Error> {
+        let projects_link = self.projects_link(url)?;
+        debug!("Searching for environment {}", environment);
+        self.find_by_name(&projects_link, environment)
+    }
+
+    /// The environments the API key can access, leaving out removed ones.
+    fn projects(&self, url: &url::Url) -> Result<Vec<Project>, Error> {
+        let index = self.index(&url)?;
+        // without all=true, which lists every environment to an admin
+        let mut projects_link = index.links.get("projects").ok_or(Error::Empty)?.clone();
+        projects_link.query_pairs_mut().append_pair("state_ne", "removed");
+        self.filter_collection(
+            &projects_link.into_inner(),
+            |p: &Project| p.state != "removed",
+        )
+    }
+
+    fn projects_link(&self, url: &url::Url) -> Result<url::Url, Error> {
         let index = self.index(&url)?;
         let mut projects_link = index.links.get("projects").ok_or(Error::Empty)?.clone();
         // workaround edge case where Rancher doesn't show any projects
         projects_link.query_pairs_mut().append_pair("all", "true");
-        debug!("Searching for environment {}", environment);
-        let project = self.find_in_collection(
-            &projects_link,
-            |p: &Project| &p.name == environment,
-        )?;
-        debug!("Searching for stack {}", stack);
-        let stacks_link = project.links.get("stacks").ok_or(Error::Empty)?;
-        let stack = self.find_in_collection(
-            stacks_link,
-            |s: &Stack| s.name == stack,
-        )?;
-        debug!("Searching for service {}", service);
-        let services_link = stack.links.get("services").ok_or(Error::Empty)?;
-        let service = self.find_in_collection(
-            services_link,
-            |s: &Service| s.name == service,
-        )?;
-        debug!("Searching for executable container");
-        let instances_link = service.links.get("instances").ok_or(Error::Empty)?;
-        self.filter_collection(instances_link, |c: &Container| {
-            c.actions.get("execute").is_some()
-        })
+        Ok(projects_link.into_inner())
     }
 
     fn index(&self, url: &url::Url) -> Result<Index, Error> {
         debug!("Connecting to {}", url);
-        let mut copy = url.clone();
-        copy.set_path("/v2-beta");
-        self.get(&copy)
+        self.get(&api_url(url, "v2-beta"))
+    }
+
+    /// The link called `name` in the API index, or `path` under the server's
+    /// URL when the index doesn't have it.
+    fn index_link(&self, url: &url::Url, name: &str, path: &str) -> Result<url::Url, Error> {
+        let index = self.index(url)?;
+        Ok(index.links.get(name).map(|l| l.clone().into_inner()).unwrap_or_else(
+            || {
+                debug2!("No {} link in the API index", name);
+                api_url(url, path)
+            },
+        ))
+    }
+
+    /// Finds the item called `name` in the collection at `url` with a single
+    /// request, having the server filter the collection. Servers that don't
+    /// support the filters are searched page by page.
+    fn find_by_name<T>(&self, url: &url::Url, name: &str) -> Result<T, Error>
+    where
+        T: Named + serde::de::DeserializeOwned,
+    {
+        let mut filtered = url.clone();
+        filtered
+            .query_pairs_mut()
+            .append_pair("name", name)
+            .append_pair("state_ne", "removed")
+            .append_pair("limit", "1");
+        match self.get::<Collection<T>>(&filtered) {
+            Ok(mut collection) => {
+                if let Some(i) = collection.data.iter().position(|x| x.name() == name) {
+                    return Ok(collection.data.remove(i));
+                }
+                let filtered_by_name = collection.filters.get("name").map_or(false, |v| !v.is_null());
+                if filtered_by_name {
+                    return Err(Error::Empty);
+                }
+                debug!("Server didn't filter by name, searching all of {}", url);
+            }
+            Err(Error::NotFound) => return Err(Error::NotFound),
+            Err(Error::Unauthorized) => return Err(Error::Unauthorized),
+            Err(e) => debug!("Filtering by name failed ({}), searching all of {}", e, url),
+        };
+        self.find_in_collection(url, |x: &T| x.name() == name)
     }
 
     fn find_in_collection<F, T>(&self, url: &url::Url, cond: F) -> Result<T, Error>
@@ -301,44 +916,83 @@ impl Client {
         T: serde::de::DeserializeOwned,
     {
         debug2!("GET {}", url);
-        let mut request = self.http.get(url.clone());
-        match self.api_key {
-            Some(ref a) => {
-                debug3!("Request Using Rancher API key {}", a.public_value);
-                request.basic_auth(a.public_value.clone(), Some(a.secret_value.clone()));
-            }
-            _ => (),
+        let response = self.send(http::Request::get(url.clone()))?;
+        if response.status == 404 {
+            return Err(Error::NotFound);
         }
-        let mut response = request.send()?;
-        debug3!("{:?}", response);
-        if response.status() == reqwest::StatusCode::Unauthorized {
-            return Err(Error::Unauthorized);
-        };
-
         Ok(response.json()?)
     }
 
+    fn delete(&self, url: &url::Url) -> Result<(), Error> {
+        debug2!("DELETE {}", url);
+        let response = self.send(http::Request::delete(url.clone()))?;
+        if response.status == 404 {
+            return Err(Error::NotFound);
+        }
+        if !response.is_success() {
+            return Err(Error::HttpStatus(response.status, response.reason));
+        }
+        Ok(())
+    }
+
     pub fn post<T, U>(&self, url: &url::Url, body: &T) -> Result<U, Error>
     where
         T: serde::Serialize,
         U: serde::de::DeserializeOwned,
     {
         debug2!("POST {}", url);
-        let mut request = self.http.post(url.clone());
-        request.json(body);
-        match self.api_key {
-            Some(ref a) => {
+        let response = self.send(http::Request::post(url.clone()).json(body)?)?;
+        Ok(response.json()?)
+    }
+
+    /// Sends `request` with the API key, authenticating and sending it again
+    /// if the key is rejected.
+    fn send(&self, request: http::Request) -> Result<http::Response, Error> {
+        let mut tries = 0;
+        loop {
+            let mut request = request.clone();
+            if let Some(ref a) = *self.api_key.borrow() {
                 debug3!("Request Using Rancher API key {}", a.public_value);
-                request.basic_auth(a.public_value.clone(), Some(a.secret_value.clone()));
+                request = request.basic_auth(&a.public_value, &a.secret_value);
             }
-            _ => (),
+            let response = self.http.send(request)?;
+            if response.status != 401 {
+                return Ok(response);
+            }
+            let next_api_key = self.other_api_keys.borrow_mut().next();
+            if let Some(api_key) = next_api_key {
+                verbose!(
+                    "Rancher API key {} was rejected, trying {}",
+                    self.api_key().map(|k| k.public_value).unwrap_or_default(),
+                    api_key.public_value
+                );
+                self.set_api_key(Some(api_key));
+                continue;
+            }
+            if tries > 0 {
+                return Err(Error::Unauthorized);
+            }
+            self.authenticate()?;
+            tries += 1;
         }
-        let mut response = request.send()?;
-        debug3!("{:?}", response);
-        if response.status() == reqwest::StatusCode::Unauthorized {
-            return Err(Error::Unauthorized);
-        };
+    }
+}
 
-        Ok(response.json()?)
+// the API is relative to the server's URL, which may have a path of its own
+fn api_url(url: &url::Url, path: &str) -> url::Url {
+    let mut base = url.clone();
+    if !base.path().ends_with('/') {
+        let with_slash = format!("{}/", base.path());
+        base.set_path(&with_slash);
     }
+    base.join(path).expect("bad API path")
+}
+
+fn link(links: &HashMap<String, url_serde::Serde<url::Url>>, name: &str) -> Result<url::Url, Error> {
+    links.get(name).map(|l| l.clone().into_inner()).ok_or(Error::Empty)
+}
+
+// names a cached link by the server and the path of names leading to it
+fn link_key(kind: &str, url: &url::Url, path: &[&str]) -> String {
+    format!("{} {}/{}", kind, url.as_str().trim_right_matches('/'), path.join("/"))
 }
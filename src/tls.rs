@@ -0,0 +1,246 @@
+extern crate base64;
+extern crate openssl;
+
+use std;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use self::openssl::hash::{hash, MessageDigest};
+use self::openssl::pkcs12::Pkcs12;
+use self::openssl::pkey::PKey;
+use self::openssl::ssl::{HandshakeError, SslConnectorBuilder, SslMethod, SslStream, SSL_VERIFY_PEER};
+use self::openssl::stack::Stack;
+use self::openssl::x509::{X509, X509Ref};
+
+use options::Options;
+
+const PEM_CERTIFICATE: &'static str = "-----BEGIN CERTIFICATE-----";
+
+#[derive(Debug)]
+pub enum Error {
+    BadCertificate(String, openssl::error::ErrorStack),
+    // the server's host:port
+    CertificateNotVerified(String),
+    HandshakeFailed(String),
+    HostNotTrusted(String),
+    IoError(String, std::io::Error),
+    NoCertificate(String),
+    SslError(openssl::error::ErrorStack),
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Error {
+        Error::SslError(err)
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::BadCertificate(_, _) => "bad certificate or key",
+            Error::CertificateNotVerified(_) => "server certificate not verified",
+            Error::HandshakeFailed(_) => "TLS handshake failed",
+            Error::HostNotTrusted(_) => "host certificate not trusted",
+            Error::IoError(_, ref err) => err.description(),
+            Error::NoCertificate(_) => "no certificate",
+            Error::SslError(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::BadCertificate(_, ref err) => Some(err as &StdError),
+            Error::IoError(_, ref err) => Some(err as &StdError),
+            Error::SslError(ref err) => Some(err as &StdError),
+            Error::CertificateNotVerified(_) |
+            Error::HandshakeFailed(_) |
+            Error::HostNotTrusted(_) |
+            Error::NoCertificate(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BadCertificate(ref path, ref err) => {
+                write!(fmt, "bad certificate or key in {}: {}", path, err)
+            }
+            Error::CertificateNotVerified(ref name) => write!(
+                fmt,
+                "couldn't verify the certificate of {}, see CACertificate in rsh_config(5)",
+                name
+            ),
+            Error::HandshakeFailed(ref err) => write!(fmt, "TLS handshake failed: {}", err),
+            Error::HostNotTrusted(ref name) => write!(fmt, "the certificate of {} isn't trusted", name),
+            Error::IoError(ref path, ref err) => write!(fmt, "couldn't read {}: {}", path, err),
+            Error::NoCertificate(ref path) => write!(fmt, "no certificate in {}", path),
+            Error::SslError(ref err) => err.fmt(fmt),
+        }
+    }
+}
+
+/// Decides whether to trust the server `name`, a `host:port`, presenting a
+/// certificate for the public key with `fingerprint`.
+pub type CheckHost = Arc<Fn(&str, &str) -> bool + Send + Sync>;
+
+/// The certificates to trust and to present to the server, whether to check
+/// the server's certificate is for its host name, and what to ask once the
+/// certificate is verified.
+#[derive(Clone)]
+pub struct Tls {
+    pub ca_certificates: Vec<Vec<u8>>, // DER
+    pub check_host: Option<CheckHost>,
+    pub identity: Option<Vec<u8>>, // PKCS #12 DER, without a password
+    pub verify_host_name: bool,
+}
+
+impl Tls {
+    /// Makes a TLS connection to the server at `host`:`port` on `stream`,
+    /// verifying its certificate, then asking `check_host` about it.
+    pub fn connect<S>(&self, host: &str, port: u16, stream: S) -> Result<SslStream<S>, Error>
+    where
+        S: Read + Write + std::any::Any + fmt::Debug,
+    {
+        let name = format!("{}:{}", host, port);
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls())?;
+        for der in &self.ca_certificates {
+            builder.cert_store_mut().add_cert(X509::from_der(der)?)?;
+        }
+        if let Some(ref der) = self.identity {
+            let identity = Pkcs12::from_der(der)?.parse("")?;
+            builder.set_certificate(&identity.cert)?;
+            builder.set_private_key(&identity.pkey)?;
+            for certificate in identity.chain {
+                builder.add_extra_chain_cert(certificate)?;
+            }
+        }
+        let rejected = Arc::new(AtomicBool::new(false));
+        if let Some(ref check_host) = self.check_host {
+            let (check_host, rejected, name) = (check_host.clone(), rejected.clone(), name.clone());
+            builder.set_verify_callback(SSL_VERIFY_PEER, move |verified, context| {
+                // the server's own certificate is verified last, after the
+                // rest of the chain
+                if !verified || context.error_depth() != 0 {
+                    return verified;
+                }
+                let trusted = context
+                    .current_cert()
+                    .and_then(|v| fingerprint(v).ok())
+                    .map_or(false, |v| check_host(&name, &v));
+                rejected.store(!trusted, Ordering::SeqCst);
+                trusted
+            });
+        }
+
+        let connector = builder.build();
+        let result = if self.verify_host_name {
+            connector.connect(host, stream)
+        } else {
+            // still saying which host is wanted, for servers with several
+            connector.configure().map_err(HandshakeError::from).and_then(|mut v| {
+                v.set_hostname(host)?;
+                v.danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
+            })
+        };
+        result.map_err(|e| match e {
+            _ if rejected.load(Ordering::SeqCst) => Error::HostNotTrusted(name),
+            HandshakeError::Failure(ref s) if s.ssl().verify_result().is_some() => {
+                debug!("Certificate of {} not verified: {}\r", name, e);
+                Error::CertificateNotVerified(name)
+            }
+            e => Error::HandshakeFailed(e.to_string()),
+        })
+    }
+}
+
+/// Reads the certificates and key named by `CACertificate`,
+/// `ClientCertificate` and `ClientKey`.
+pub fn load(options: &Options) -> Result<Tls, Error> {
+    let mut ca_certificates = Vec::new();
+    if let Some(ref path) = options.ca_certificate {
+        for certificate in read_certificates(path)? {
+            let der = certificate.to_der().map_err(|e| Error::BadCertificate(path.clone(), e))?;
+            ca_certificates.push(der);
+        }
+        debug!("Trusting {} certificates from {}", ca_certificates.len(), path);
+    }
+    let identity = match options.client_certificate {
+        Some(ref path) => {
+            // the key may follow the certificate in the same file
+            let key_path = options.client_key.as_ref().unwrap_or(path);
+            debug!("Presenting certificate {} with key {}", path, key_path);
+            Some(identity(path, key_path)?)
+        }
+        None => None,
+    };
+    Ok(Tls {
+        ca_certificates,
+        check_host: None,
+        identity,
+        verify_host_name: options.check_host_name,
+    })
+}
+
+// the SHA-256 fingerprint of the public key in `certificate`, its DER
+// SubjectPublicKeyInfo, as `SHA256:` and unpadded base64 like ssh's
+fn fingerprint(certificate: &X509Ref) -> Result<String, Error> {
+    let key = certificate.public_key()?.public_key_to_der()?;
+    let digest = hash(MessageDigest::sha256(), &key)?;
+    Ok(format!(
+        "SHA256:{}",
+        base64::encode(&digest).trim_right_matches('=')
+    ))
+}
+
+// the client certificate and key are kept together as PKCS #12, as neither
+// can be shared between threads, including any chain of certificates
+// following the client's own
+fn identity(certificate_path: &str, key_path: &str) -> Result<Vec<u8>, Error> {
+    let mut certificates = read_certificates(certificate_path)?.into_iter();
+    let certificate = certificates.next().ok_or_else(|| {
+        Error::NoCertificate(certificate_path.to_string())
+    })?;
+    let key = PKey::private_key_from_pem(&read(key_path)?).map_err(|e| {
+        Error::BadCertificate(key_path.to_string(), e)
+    })?;
+
+    let bad_certificate = |e| Error::BadCertificate(certificate_path.to_string(), e);
+    let mut chain = Stack::new().map_err(&bad_certificate)?;
+    for v in certificates {
+        chain.push(v).map_err(&bad_certificate)?;
+    }
+    let mut builder = Pkcs12::builder();
+    builder.ca(chain);
+    builder
+        .build("", "rsh", &key, &certificate)
+        .and_then(|v| v.to_der())
+        .map_err(&bad_certificate)
+}
+
+// X509::stack_from_pem can't tell the end of the file from an error with
+// OpenSSL 3, so each certificate is read on its own
+fn read_certificates(path: &str) -> Result<Vec<X509>, Error> {
+    let pem = String::from_utf8_lossy(&read(path)?).into_owned();
+    let mut certificates = Vec::new();
+    for block in pem.split(PEM_CERTIFICATE).skip(1) {
+        let certificate = X509::from_pem(format!("{}{}", PEM_CERTIFICATE, block).as_bytes())
+            .map_err(|e| Error::BadCertificate(path.to_string(), e))?;
+        certificates.push(certificate);
+    }
+    if certificates.is_empty() {
+        return Err(Error::NoCertificate(path.to_string()));
+    }
+    Ok(certificates)
+}
+
+fn read(path: &str) -> Result<Vec<u8>, Error> {
+    let mut buffer = Vec::new();
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buffer))
+        .map_err(|e| Error::IoError(path.to_string(), e))?;
+    Ok(buffer)
+}